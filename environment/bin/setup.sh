@@ -66,9 +66,12 @@ echo
 # Needs to be before Rustbier starts - configure access control for Minio
 echo "Setting up Minio..."
 MINIO_BUCKET=local/apollo
+MINIO_DERIVATIVES_BUCKET=local/apollo-derivatives
 "${DOCKER[@]}" exec "${container_s3}" /opt/mc config host add local http://127.0.0.1:9000 apollousr apollopwd
 "${DOCKER[@]}" exec "${container_s3}" /opt/mc mb --region "eu-west-1" "${MINIO_BUCKET}"
 "${DOCKER[@]}" exec "${container_s3}" /opt/mc policy download "${MINIO_BUCKET}"
+"${DOCKER[@]}" exec "${container_s3}" /opt/mc mb --region "eu-west-1" "${MINIO_DERIVATIVES_BUCKET}"
+"${DOCKER[@]}" exec "${container_s3}" /opt/mc policy download "${MINIO_DERIVATIVES_BUCKET}"
 
 for f in tests/resources/*; do
     filename=$(echo $f | cut -d'/' -f3)
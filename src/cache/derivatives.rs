@@ -0,0 +1,202 @@
+use crate::cache::{CacheKey, CachedImage};
+use crate::commons::conditional::Validators;
+use crate::commons::s3::new_client;
+use crate::commons::{DerivativesConfig, ImageFormat};
+use futures::future::{Either, Future};
+use futures::Stream;
+use rusoto_core::RusotoError;
+use rusoto_s3::{GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, S3};
+use std::collections::HashMap;
+
+const ETAG_METADATA: &str = "etag";
+const LAST_MODIFIED_METADATA: &str = "last-modified";
+
+#[derive(Clone)]
+pub struct DerivativesStore {
+    client: S3Client,
+    bucket: String,
+}
+
+impl DerivativesStore {
+    pub fn new(config: &DerivativesConfig) -> Self {
+        DerivativesStore {
+            client: new_client(
+                config.region.clone(),
+                config.access_key_id.as_ref().map(String::as_str),
+                config.secret_access_key.as_ref().map(String::as_str),
+            ),
+            bucket: config.bucket.clone(),
+        }
+    }
+
+    pub fn get(&self, key: &CacheKey) -> impl Future<Item = Option<CachedImage>, Error = ()> {
+        let object_key = key.derivative_key();
+        debug!("Fetching derivative {} from S3 bucket: {}", object_key, self.bucket);
+        self.client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: object_key.clone(),
+                ..Default::default()
+            })
+            .then(move |res| match res {
+                Ok(output) => {
+                    let format = output
+                        .content_type
+                        .as_ref()
+                        .and_then(|content_type| ImageFormat::from_content_type(content_type));
+                    let metadata = output.metadata.unwrap_or_default();
+                    let validators = Validators {
+                        etag: metadata.get(ETAG_METADATA).cloned(),
+                        last_modified: metadata.get(LAST_MODIFIED_METADATA).cloned(),
+                    };
+                    match (format, output.body) {
+                        (Some(format), Some(body)) => Either::A(
+                            body.concat2()
+                                .map(move |body| {
+                                    Some(CachedImage {
+                                        format,
+                                        body,
+                                        validators,
+                                    })
+                                })
+                                .map_err(|e| {
+                                    error!("Error fetching derivative from S3: {:?}", e);
+                                }),
+                        ),
+                        _ => {
+                            error!("Derivative {} has no valid image", object_key);
+                            Either::B(futures::ok(None))
+                        }
+                    }
+                }
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                    Either::B(futures::ok(None))
+                }
+                Err(e) => {
+                    error!("Error fetching derivative from S3: {:?}", e);
+                    Either::B(futures::ok(None))
+                }
+            })
+    }
+
+    pub fn put(&self, key: &CacheKey, image: &CachedImage) -> impl Future<Item = (), Error = ()> {
+        let object_key = key.derivative_key();
+        let mut metadata = HashMap::new();
+        if let Some(etag) = &image.validators.etag {
+            metadata.insert(ETAG_METADATA.to_string(), etag.clone());
+        }
+        if let Some(last_modified) = &image.validators.last_modified {
+            metadata.insert(LAST_MODIFIED_METADATA.to_string(), last_modified.clone());
+        }
+        debug!("Storing derivative {} in S3 bucket: {}", object_key, self.bucket);
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: object_key,
+                body: Some(image.body.to_vec().into()),
+                content_type: Some(format!("image/{}", image.format)),
+                metadata: Some(metadata),
+                ..Default::default()
+            })
+            .map(|_| ())
+            .map_err(|e| {
+                error!("Error storing derivative in S3: {:?}", e);
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::stub;
+    use actix_rt::System;
+    use actix_web::web::Bytes;
+    use rusoto_core::Region;
+
+    fn store(endpoint: String) -> DerivativesStore {
+        DerivativesStore::new(&DerivativesConfig {
+            region: Region::Custom {
+                name: "test".to_string(),
+                endpoint,
+            },
+            bucket: "derivatives".to_string(),
+            access_key_id: Some("key".to_string()),
+            secret_access_key: Some("secret".to_string()),
+        })
+    }
+
+    fn cache_key() -> CacheKey {
+        CacheKey {
+            source: "apollo".to_string(),
+            key: "listings/abc.jpg".to_string(),
+            transformation: "size=100xauto".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_derivative_key() {
+        let key = cache_key();
+        assert_eq!(key.derivative_key(), cache_key().derivative_key());
+        assert!(key.derivative_key().starts_with("apollo/listings/abc.jpg/"));
+        let other = CacheKey {
+            transformation: "size=200xauto".to_string(),
+            ..cache_key()
+        };
+        assert_ne!(key.derivative_key(), other.derivative_key());
+    }
+
+    #[test]
+    fn test_get_derivative() {
+        let (endpoint, requests) = stub::serve(
+            "200 OK",
+            "Content-Type: image/png\r\nx-amz-meta-etag: \"abc\"\r\n",
+            b"png",
+        );
+        let store = store(endpoint);
+        let image = System::new("test")
+            .block_on(futures::lazy(|| store.get(&cache_key())))
+            .expect("Unable to fetch derivative")
+            .expect("Derivative should exist");
+        assert_eq!(image.body, Bytes::from_static(b"png"));
+        assert_eq!(format!("{}", image.format), "png");
+        assert_eq!(image.validators.etag, Some("\"abc\"".to_string()));
+        let request_line = requests.recv().unwrap();
+        assert!(request_line.starts_with(&format!(
+            "GET /derivatives/{}",
+            cache_key().derivative_key()
+        )));
+    }
+
+    #[test]
+    fn test_get_missing_derivative() {
+        let (endpoint, _) = stub::serve(
+            "404 Not Found",
+            "Content-Type: application/xml\r\n",
+            b"<?xml version=\"1.0\" encoding=\"UTF-8\"?><Error><Code>NoSuchKey</Code><Message>The specified key does not exist.</Message></Error>",
+        );
+        let store = store(endpoint);
+        let image = System::new("test")
+            .block_on(futures::lazy(|| store.get(&cache_key())))
+            .expect("Unable to fetch derivative");
+        assert!(image.is_none());
+    }
+
+    #[test]
+    fn test_put_derivative() {
+        let (endpoint, requests) = stub::serve("200 OK", "", b"");
+        let store = store(endpoint);
+        let image = CachedImage {
+            format: ImageFormat::Webp,
+            body: Bytes::from_static(b"webp"),
+            validators: Validators::default(),
+        };
+        System::new("test")
+            .block_on(futures::lazy(|| store.put(&cache_key(), &image)))
+            .expect("Unable to store derivative");
+        let request_line = requests.recv().unwrap();
+        assert!(request_line.starts_with(&format!(
+            "PUT /derivatives/{}",
+            cache_key().derivative_key()
+        )));
+    }
+}
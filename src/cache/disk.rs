@@ -0,0 +1,310 @@
+use crate::cache::CachedImage;
+use crate::commons::conditional::Validators;
+use crate::commons::sha1_hex;
+use crate::commons::{DiskCacheConfig, ImageFormat};
+use actix_web::web::Bytes;
+use linked_hash_map::LinkedHashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const TMP_DIR: &str = "tmp";
+
+/// Files are a JSON header line followed by the image, named after the SHA-1 of their key.
+pub struct DiskCache {
+    directory: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    index: Mutex<Index>,
+    tmp_counter: AtomicUsize,
+}
+
+struct Index {
+    files: LinkedHashMap<String, IndexEntry>,
+    size: u64,
+}
+
+struct IndexEntry {
+    size: u64,
+    modified: SystemTime,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntryHeader {
+    format: ImageFormat,
+    #[serde(default)]
+    validators: Validators,
+}
+
+fn is_cache_file_name(name: &str) -> bool {
+    name.len() == 40 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl DiskCache {
+    pub fn open(config: &DiskCacheConfig) -> io::Result<Self> {
+        let directory = PathBuf::from(&config.directory);
+        let tmp_directory = directory.join(TMP_DIR);
+        if tmp_directory.exists() {
+            fs::remove_dir_all(&tmp_directory)?;
+        }
+        fs::create_dir_all(&tmp_directory)?;
+
+        let cache = DiskCache {
+            directory,
+            max_bytes: config.max_bytes,
+            max_age: config.max_age_secs.map(Duration::from_secs),
+            index: Mutex::new(Index {
+                files: LinkedHashMap::new(),
+                size: 0,
+            }),
+            tmp_counter: AtomicUsize::new(0),
+        };
+        cache.rebuild_index()?;
+        Ok(cache)
+    }
+
+    fn rebuild_index(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if metadata.is_file() && is_cache_file_name(&name) {
+                files.push((name, metadata.len(), metadata.modified()?));
+            }
+        }
+        // Without access times, the oldest files are treated as the least recently used
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let evicted = {
+            let mut index = self.index.lock().expect("Disk cache lock poisoned");
+            for (name, size, modified) in files {
+                index.size += size;
+                index.files.insert(name, IndexEntry { size, modified });
+            }
+            info!(
+                "Disk cache index rebuilt with {} files ({} bytes)",
+                index.files.len(),
+                index.size
+            );
+            self.evict(&mut index)
+        };
+        self.remove_files(evicted);
+        Ok(())
+    }
+
+    fn is_expired(&self, entry: &IndexEntry) -> bool {
+        match (self.max_age, entry.modified.elapsed()) {
+            (Some(max_age), Ok(age)) => age >= max_age,
+            _ => false,
+        }
+    }
+
+    fn evict(&self, index: &mut Index) -> Vec<String> {
+        let expired = index
+            .files
+            .iter()
+            .filter(|(_, entry)| self.is_expired(entry))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in &expired {
+            if let Some(entry) = index.files.remove(name) {
+                index.size -= entry.size;
+            }
+        }
+
+        let mut evicted = expired;
+        while index.size > self.max_bytes {
+            match index.files.pop_front() {
+                Some((name, entry)) => {
+                    index.size -= entry.size;
+                    evicted.push(name);
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    fn remove_files(&self, names: Vec<String>) {
+        for name in names {
+            debug!("Evicting {} from the disk cache", name);
+            if let Err(e) = fs::remove_file(self.directory.join(&name)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!("Error evicting {} from the disk cache: {:?}", name, e);
+                }
+            }
+        }
+    }
+
+    fn forget(&self, name: &str) {
+        let mut index = self.index.lock().expect("Disk cache lock poisoned");
+        if let Some(entry) = index.files.remove(name) {
+            index.size -= entry.size;
+        }
+    }
+
+    pub fn get(&self, key: &str) -> io::Result<Option<CachedImage>> {
+        let name = sha1_hex(key);
+        let expired = {
+            let mut index = self.index.lock().expect("Disk cache lock poisoned");
+            match index.files.get_refresh(&name) {
+                Some(entry) => self.is_expired(entry),
+                None => return Ok(None),
+            }
+        };
+        if expired {
+            self.forget(&name);
+            self.remove_files(vec![name]);
+            return Ok(None);
+        }
+
+        let content = match fs::read(self.directory.join(&name)) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.forget(&name);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let header_end = content
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing cache header"))?;
+        let header: EntryHeader = serde_json::from_slice(&content[..header_end])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some(CachedImage {
+            format: header.format,
+            body: Bytes::from(&content[header_end + 1..]),
+            validators: header.validators,
+        }))
+    }
+
+    pub fn insert(&self, key: &str, image: &CachedImage) -> io::Result<()> {
+        let name = sha1_hex(key);
+        let mut content = serde_json::to_vec(&EntryHeader {
+            format: image.format,
+            validators: image.validators.clone(),
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        content.push(b'\n');
+        content.extend_from_slice(&image.body);
+        let size = content.len() as u64;
+        if size > self.max_bytes {
+            debug!("Image {} is too large for the disk cache", key);
+            return Ok(());
+        }
+
+        // Write to a temporary file first, so readers never see a partially written image
+        let tmp_path = self.directory.join(TMP_DIR).join(format!(
+            "{}.{}.{}",
+            name,
+            process::id(),
+            self.tmp_counter.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&tmp_path, &content)?;
+        if let Err(e) = fs::rename(&tmp_path, self.directory.join(&name)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        let evicted = {
+            let mut index = self.index.lock().expect("Disk cache lock poisoned");
+            if let Some(previous) = index.files.remove(&name) {
+                index.size -= previous.size;
+            }
+            index.files.insert(
+                name,
+                IndexEntry {
+                    size,
+                    modified: SystemTime::now(),
+                },
+            );
+            index.size += size;
+            self.evict(&mut index)
+        };
+        self.remove_files(evicted);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config(name: &str, max_bytes: u64, max_age_secs: Option<u64>) -> DiskCacheConfig {
+        let directory = env::temp_dir().join(format!("rustbier-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        DiskCacheConfig {
+            directory: directory.to_string_lossy().into_owned(),
+            max_bytes,
+            max_age_secs,
+        }
+    }
+
+    fn image(size: usize) -> CachedImage {
+        CachedImage {
+            format: ImageFormat::Webp,
+            body: Bytes::from(vec![1; size]),
+            validators: Validators {
+                etag: Some("\"abc\"".to_string()),
+                last_modified: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_get_inserted_image() {
+        let config = config("disk-get", 1000, None);
+        let cache = DiskCache::open(&config).unwrap();
+        cache.insert("a", &image(10)).unwrap();
+        let cached = cache.get("a").unwrap().expect("Image should be cached");
+        assert_eq!(cached.body, Bytes::from(vec![1; 10]));
+        assert_eq!(format!("{}", cached.format), "webp");
+        assert_eq!(cached.validators.etag, Some("\"abc\"".to_string()));
+        assert!(cache.get("b").unwrap().is_none());
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_index() {
+        let config = config("disk-rebuild", 1000, None);
+        {
+            let cache = DiskCache::open(&config).unwrap();
+            cache.insert("a", &image(10)).unwrap();
+        }
+        let cache = DiskCache::open(&config).unwrap();
+        assert!(cache.get("a").unwrap().is_some());
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let config = config("disk-lru", 300, None);
+        let cache = DiskCache::open(&config).unwrap();
+        cache.insert("a", &image(20)).unwrap();
+        cache.insert("b", &image(20)).unwrap();
+        cache.insert("c", &image(20)).unwrap();
+        assert!(cache.get("a").unwrap().is_some());
+        cache.insert("d", &image(20)).unwrap();
+        assert!(cache.get("a").unwrap().is_some());
+        assert!(cache.get("b").unwrap().is_none());
+        assert!(cache.get("c").unwrap().is_some());
+        assert!(cache.get("d").unwrap().is_some());
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_evict_expired() {
+        let config = config("disk-expired", 1000, Some(0));
+        let cache = DiskCache::open(&config).unwrap();
+        cache.insert("a", &image(10)).unwrap();
+        assert!(cache.get("a").unwrap().is_none());
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use crate::cache::CachedImage;
+use linked_hash_map::LinkedHashMap;
+use std::sync::Mutex;
+
+pub struct MemoryCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+}
+
+struct Entries {
+    images: LinkedHashMap<String, CachedImage>,
+    size: usize,
+}
+
+fn entry_size(key: &str, image: &CachedImage) -> usize {
+    key.len() + image.body.len()
+}
+
+impl MemoryCache {
+    pub fn new(max_bytes: usize) -> Self {
+        MemoryCache {
+            entries: Mutex::new(Entries {
+                images: LinkedHashMap::new(),
+                size: 0,
+            }),
+            max_bytes,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedImage> {
+        let mut entries = self.entries.lock().expect("Memory cache lock poisoned");
+        entries.images.get_refresh(key).cloned()
+    }
+
+    pub fn insert(&self, key: String, image: CachedImage) {
+        let size = entry_size(&key, &image);
+        if size > self.max_bytes {
+            debug!("Image {} is too large for the memory cache", key);
+            return;
+        }
+        let mut entries = self.entries.lock().expect("Memory cache lock poisoned");
+        if let Some(previous) = entries.images.remove(&key) {
+            entries.size -= entry_size(&key, &previous);
+        }
+        entries.images.insert(key, image);
+        entries.size += size;
+        while entries.size > self.max_bytes {
+            match entries.images.pop_front() {
+                Some((evicted_key, evicted)) => {
+                    debug!("Evicting {} from the memory cache", evicted_key);
+                    entries.size -= entry_size(&evicted_key, &evicted);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::conditional::Validators;
+    use crate::commons::ImageFormat;
+    use actix_web::web::Bytes;
+
+    fn image(size: usize) -> CachedImage {
+        CachedImage {
+            format: ImageFormat::Jpeg,
+            body: Bytes::from(vec![0; size]),
+            validators: Validators::default(),
+        }
+    }
+
+    #[test]
+    fn test_get_inserted_image() {
+        let cache = MemoryCache::new(100);
+        cache.insert("a".to_string(), image(10));
+        assert_eq!(cache.get("a").map(|i| i.body.len()), Some(10));
+        assert!(cache.get("b").is_none());
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let cache = MemoryCache::new(33);
+        cache.insert("a".to_string(), image(10));
+        cache.insert("b".to_string(), image(10));
+        cache.insert("c".to_string(), image(10));
+        assert!(cache.get("a").is_some());
+        cache.insert("d".to_string(), image(10));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("d").is_some());
+    }
+
+    #[test]
+    fn test_evict_by_size() {
+        let cache = MemoryCache::new(33);
+        cache.insert("a".to_string(), image(10));
+        cache.insert("b".to_string(), image(10));
+        cache.insert("c".to_string(), image(25));
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn test_skip_oversized_image() {
+        let cache = MemoryCache::new(10);
+        cache.insert("a".to_string(), image(5));
+        cache.insert("b".to_string(), image(20));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+    }
+
+    #[test]
+    fn test_replace_image() {
+        let cache = MemoryCache::new(24);
+        cache.insert("a".to_string(), image(10));
+        cache.insert("a".to_string(), image(20));
+        assert_eq!(cache.get("a").map(|i| i.body.len()), Some(20));
+    }
+}
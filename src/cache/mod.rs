@@ -0,0 +1,261 @@
+pub mod derivatives;
+pub mod disk;
+pub mod memory;
+
+use crate::commons::conditional::Validators;
+use crate::commons::negotiation::AcceptedFormats;
+use crate::commons::{sha1_hex, CacheConfig, ImageFormat, ProcessImageRequest};
+use crate::image_processor::EncodeOptions;
+use actix_web::web;
+use actix_web::web::Bytes;
+use derivatives::DerivativesStore;
+use disk::DiskCache;
+use futures::future::{Either, Future};
+use memory::MemoryCache;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct CachedImage {
+    pub format: ImageFormat,
+    pub body: Bytes,
+    pub validators: Validators,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    source: String,
+    key: String,
+    transformation: String,
+}
+
+impl CacheKey {
+    /// `accepted` is only part of the key when the format is negotiated.
+    pub fn new(
+        source: &str,
+        key: &str,
+        request: &ProcessImageRequest,
+        options: &EncodeOptions,
+        accepted: &AcceptedFormats,
+    ) -> Self {
+        let mut transformation = format!("{};{}", request.canonical(), options.canonical());
+        if request.format == ImageFormat::Auto {
+            transformation.push_str(&format!(";accept={}", accepted.canonical()));
+        }
+        CacheKey {
+            source: source.to_string(),
+            key: key.to_string(),
+            transformation,
+        }
+    }
+
+    pub fn transformation(&self) -> &str {
+        &self.transformation
+    }
+
+    pub fn derivative_key(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.source,
+            self.key,
+            sha1_hex(&self.transformation)
+        )
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}?{}", self.source, self.key, self.transformation)
+    }
+}
+
+#[derive(Clone)]
+struct LocalTiers {
+    memory: Option<Arc<MemoryCache>>,
+    disk: Option<Arc<DiskCache>>,
+}
+
+impl LocalTiers {
+    fn insert(&self, key: String, image: CachedImage) {
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            let key = key.clone();
+            let image = image.clone();
+            actix_rt::spawn(web::block(move || disk.insert(&key, &image)).map_err(|e| {
+                error!("Error writing to the disk cache: {:?}", e);
+            }));
+        }
+        if let Some(memory) = &self.memory {
+            memory.insert(key, image);
+        }
+    }
+}
+
+pub struct ImageCache {
+    local: LocalTiers,
+    derivatives: Option<DerivativesStore>,
+    hits: IntCounterVec,
+    misses: IntCounterVec,
+}
+
+impl ImageCache {
+    pub fn new(config: &CacheConfig) -> io::Result<Self> {
+        let hits = IntCounterVec::new(
+            Opts::new("cache_hits_total", "Processed images served from the cache")
+                .namespace("rustbier"),
+            &["tier"],
+        )
+        .expect("Failed to create cache hits metric");
+        let misses = IntCounterVec::new(
+            Opts::new("cache_misses_total", "Processed images not found in the cache")
+                .namespace("rustbier"),
+            &["tier"],
+        )
+        .expect("Failed to create cache misses metric");
+        let disk = match &config.disk {
+            Some(disk) => Some(Arc::new(DiskCache::open(disk)?)),
+            None => None,
+        };
+        Ok(ImageCache {
+            local: LocalTiers {
+                memory: config
+                    .memory
+                    .as_ref()
+                    .map(|memory| Arc::new(MemoryCache::new(memory.max_bytes))),
+                disk,
+            },
+            derivatives: config.derivatives.as_ref().map(DerivativesStore::new),
+            hits,
+            misses,
+        })
+    }
+
+    pub fn register_metrics(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.hits.clone()))?;
+        registry.register(Box::new(self.misses.clone()))
+    }
+
+    fn get_from_memory(&self, key: &str) -> Option<CachedImage> {
+        let memory = self.local.memory.as_ref()?;
+        match memory.get(key) {
+            Some(image) => {
+                debug!("Memory cache hit for {}", key);
+                self.hits.with_label_values(&["memory"]).inc();
+                Some(image)
+            }
+            None => {
+                self.misses.with_label_values(&["memory"]).inc();
+                None
+            }
+        }
+    }
+
+    fn get_from_disk(
+        &self,
+        key: String,
+    ) -> impl Future<Item = Option<CachedImage>, Error = actix_web::Error> {
+        let disk = match &self.local.disk {
+            Some(disk) => disk.clone(),
+            None => return Either::A(futures::ok(None)),
+        };
+        let memory = self.local.memory.clone();
+        let hits = self.hits.clone();
+        let misses = self.misses.clone();
+        Either::B(
+            web::block(move || -> io::Result<Option<CachedImage>> {
+                let image = disk.get(&key)?;
+                if let (Some(image), Some(memory)) = (&image, memory) {
+                    memory.insert(key, image.clone());
+                }
+                Ok(image)
+            })
+            .then(move |res| {
+                let image = res.unwrap_or_else(|e| {
+                    error!("Error reading from the disk cache: {:?}", e);
+                    None
+                });
+                match image {
+                    Some(_) => hits.with_label_values(&["disk"]).inc(),
+                    None => misses.with_label_values(&["disk"]).inc(),
+                }
+                Ok(image)
+            }),
+        )
+    }
+
+    /// Derivatives are only served while `current` resolves to their validators.
+    fn get_from_derivatives<F, R>(
+        &self,
+        key: CacheKey,
+        current: F,
+    ) -> impl Future<Item = Option<CachedImage>, Error = actix_web::Error>
+    where
+        F: FnOnce() -> R + 'static,
+        R: Future<Item = Option<Validators>, Error = actix_web::Error> + 'static,
+    {
+        let derivatives = match &self.derivatives {
+            Some(derivatives) => derivatives.clone(),
+            None => return Either::A(futures::ok(None)),
+        };
+        let local = self.local.clone();
+        let hits = self.hits.clone();
+        let misses = self.misses.clone();
+        let cache_key = key.to_string();
+        // Deferred, so the bucket is only queried when the local tiers miss
+        let lookup = futures::lazy(move || derivatives.get(&key))
+            .then(|res| Ok::<_, actix_web::Error>(res.unwrap_or(None)))
+            .and_then(move |image| match image {
+                Some(image) => Either::A(current().map(move |validators| match validators {
+                    Some(ref validators) if *validators != image.validators => {
+                        debug!("Derivative {} is stale", cache_key);
+                        None
+                    }
+                    _ => Some((cache_key, image)),
+                })),
+                None => Either::B(futures::ok(None)),
+            });
+        Either::B(lookup.map(move |found| match found {
+            Some((cache_key, image)) => {
+                hits.with_label_values(&["derivatives"]).inc();
+                local.insert(cache_key, image.clone());
+                Some(image)
+            }
+            None => {
+                misses.with_label_values(&["derivatives"]).inc();
+                None
+            }
+        }))
+    }
+
+    /// `current` is only called on a derivatives hit.
+    pub fn get<F, R>(
+        &self,
+        key: &CacheKey,
+        current: F,
+    ) -> impl Future<Item = Option<CachedImage>, Error = actix_web::Error>
+    where
+        F: FnOnce() -> R + 'static,
+        R: Future<Item = Option<Validators>, Error = actix_web::Error> + 'static,
+    {
+        if let Some(image) = self.get_from_memory(&key.to_string()) {
+            return Either::A(futures::ok(Some(image)));
+        }
+        let from_derivatives = self.get_from_derivatives(key.clone(), current);
+        Either::B(
+            self.get_from_disk(key.to_string())
+                .and_then(move |image| match image {
+                    Some(image) => Either::A(futures::ok(Some(image))),
+                    None => Either::B(from_derivatives),
+                }),
+        )
+    }
+
+    pub fn insert(&self, key: &CacheKey, image: CachedImage) {
+        if let Some(derivatives) = &self.derivatives {
+            actix_rt::spawn(derivatives.put(key, &image));
+        }
+        self.local.insert(key.to_string(), image);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::commons::sha1_hex;
+use actix_web::http::header::{self, HttpDate};
+use actix_web::HttpRequest;
+use std::time::SystemTime;
+
+/// Values clients use to revalidate a cached image.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Derives a strong ETag for a processed image from the ETag of its original and the
+/// canonical transformation applied to it.
+pub fn compute_etag(source_etag: &str, transformation: &str) -> String {
+    format!(
+        "\"{}\"",
+        sha1_hex(&format!("{}\n{}", source_etag, transformation))
+    )
+}
+
+/// Validators of a processed image, from the validators of its original and of its watermarks.
+/// It only has an ETag when they all have one, and was last modified when the latest of them
+/// was.
+pub fn derive_validators(
+    original: &Validators,
+    watermarks: &[Validators],
+    transformation: &str,
+) -> Validators {
+    let sources = || std::iter::once(original).chain(watermarks);
+    let etags = sources()
+        .map(|validators| validators.etag.as_ref().map(String::as_str))
+        .collect::<Option<Vec<_>>>();
+    let last_modified = sources()
+        .map(|validators| {
+            let last_modified = validators.last_modified.as_ref()?;
+            parse_date(last_modified).map(|date| (date, last_modified))
+        })
+        .collect::<Option<Vec<_>>>()
+        .and_then(|dates| dates.into_iter().max_by_key(|(date, _)| *date))
+        .map(|(_, last_modified)| last_modified.clone());
+    Validators {
+        etag: etags.map(|etags| compute_etag(&etags.join("\n"), transformation)),
+        last_modified,
+    }
+}
+
+fn parse_date(date: &str) -> Option<SystemTime> {
+    date.parse::<HttpDate>().ok().map(SystemTime::from)
+}
+
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether the request has any of the conditional headers `is_not_modified` checks.
+pub fn is_conditional(req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    headers.contains_key(header::IF_NONE_MATCH) || headers.contains_key(header::IF_MODIFIED_SINCE)
+}
+
+/// Checks the conditional headers of a request against the validators of an image.
+/// `If-Modified-Since` is only evaluated when the request has no `If-None-Match`.
+pub fn is_not_modified(req: &HttpRequest, validators: &Validators) -> bool {
+    let headers = req.headers();
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return match (if_none_match.to_str(), &validators.etag) {
+            (Ok(if_none_match), Some(etag)) => matches_etag(if_none_match, etag),
+            _ => false,
+        };
+    }
+    let if_modified_since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|since| since.to_str().ok())
+        .and_then(parse_date);
+    let last_modified = validators
+        .last_modified
+        .as_ref()
+        .and_then(|last_modified| parse_date(last_modified));
+    match (if_modified_since, last_modified) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_compute_etag() {
+        assert_eq!(compute_etag("a", "size=1x1"), compute_etag("a", "size=1x1"));
+        assert_ne!(compute_etag("a", "size=1x1"), compute_etag("b", "size=1x1"));
+        assert_ne!(compute_etag("a", "size=1x1"), compute_etag("a", "size=2x2"));
+        assert!(compute_etag("a", "size=1x1").starts_with('"'));
+    }
+
+    #[test]
+    fn test_derive_validators() {
+        let watermark = Validators {
+            etag: Some("\"wm\"".to_string()),
+            last_modified: Some("Thu, 22 Oct 2015 07:28:00 GMT".to_string()),
+        };
+        let derived = derive_validators(&validators(), &[], "size=1x1");
+        assert_eq!(derived.etag, Some(compute_etag("\"abc\"", "size=1x1")));
+        assert_eq!(derived.last_modified, validators().last_modified);
+        let watermarked = derive_validators(&validators(), &[watermark.clone()], "size=1x1");
+        assert!(watermarked.etag.is_some());
+        assert_ne!(watermarked.etag, derived.etag);
+        assert_eq!(watermarked.last_modified, watermark.last_modified);
+        let changed = Validators {
+            etag: Some("\"wm2\"".to_string()),
+            ..watermark
+        };
+        assert_ne!(
+            derive_validators(&validators(), &[changed], "size=1x1").etag,
+            watermarked.etag
+        );
+        let unknown = derive_validators(&validators(), &[Validators::default()], "size=1x1");
+        assert_eq!(unknown.etag, None);
+        assert_eq!(unknown.last_modified, None);
+    }
+
+    #[test]
+    fn test_is_conditional() {
+        assert!(!is_conditional(&TestRequest::default().to_http_request()));
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"abc\"")
+            .to_http_request();
+        assert!(is_conditional(&req));
+        let req = TestRequest::default()
+            .header(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")
+            .to_http_request();
+        assert!(is_conditional(&req));
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"abc\"")
+            .to_http_request();
+        assert!(is_not_modified(&req, &validators()));
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"xyz\", W/\"abc\"")
+            .to_http_request();
+        assert!(is_not_modified(&req, &validators()));
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "*")
+            .to_http_request();
+        assert!(is_not_modified(&req, &validators()));
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"xyz\"")
+            .to_http_request();
+        assert!(!is_not_modified(&req, &validators()));
+        assert!(!is_not_modified(&req, &Validators::default()));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let req = TestRequest::default()
+            .header(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")
+            .to_http_request();
+        assert!(is_not_modified(&req, &validators()));
+        let req = TestRequest::default()
+            .header(header::IF_MODIFIED_SINCE, "Thu, 22 Oct 2015 07:28:00 GMT")
+            .to_http_request();
+        assert!(is_not_modified(&req, &validators()));
+        let req = TestRequest::default()
+            .header(header::IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")
+            .to_http_request();
+        assert!(!is_not_modified(&req, &validators()));
+        let req = TestRequest::default()
+            .header(header::IF_MODIFIED_SINCE, "not a date")
+            .to_http_request();
+        assert!(!is_not_modified(&req, &validators()));
+    }
+
+    #[test]
+    fn test_if_none_match_takes_precedence() {
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"xyz\"")
+            .header(header::IF_MODIFIED_SINCE, "Thu, 22 Oct 2015 07:28:00 GMT")
+            .to_http_request();
+        assert!(!is_not_modified(&req, &validators()));
+    }
+
+    #[test]
+    fn test_unconditional_request() {
+        let req = TestRequest::default().to_http_request();
+        assert!(!is_not_modified(&req, &validators()));
+    }
+}
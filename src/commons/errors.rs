@@ -1,8 +1,13 @@
-use crate::commons::Size;
+use crate::commons::{Crop, Size};
 use std::convert::From;
 use std::error::Error;
 use std::fmt;
 
+/// Code of `opencv::Error`s caused by an invalid request, answered with a 400.
+pub const INVALID_REQUEST_CODE: i32 = -1400;
+/// Code of `opencv::Error`s caused by an input image in an unsupported format, answered with a 415.
+pub const UNSUPPORTED_FORMAT_CODE: i32 = -1415;
+
 #[derive(Debug, PartialEq)]
 pub struct InvalidSizeError {
     msg: String,
@@ -13,6 +18,26 @@ pub struct MagickError {
     msg: String,
 }
 
+#[derive(Debug, PartialEq)]
+pub struct InvalidKeyError {
+    msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnsupportedFormatError {
+    msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidPageError {
+    msg: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidCropError {
+    msg: String,
+}
+
 impl InvalidSizeError {
     pub fn new(size: &Size) -> InvalidSizeError {
         let message = format!("Size {:?} is not valid.", &size);
@@ -26,6 +51,75 @@ impl fmt::Display for InvalidSizeError {
     }
 }
 
+impl InvalidKeyError {
+    pub fn new(key: &str) -> InvalidKeyError {
+        let message = format!("Key {:?} is not valid.", key);
+        InvalidKeyError { msg: message }
+    }
+}
+
+impl fmt::Display for InvalidKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl UnsupportedFormatError {
+    pub fn new(reason: &str) -> UnsupportedFormatError {
+        let message = format!("The image is not supported: {}", reason);
+        UnsupportedFormatError { msg: message }
+    }
+}
+
+impl Default for UnsupportedFormatError {
+    fn default() -> UnsupportedFormatError {
+        UnsupportedFormatError {
+            msg: String::from("The format of the image is not supported."),
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl InvalidPageError {
+    pub fn new(page: usize, pages: usize) -> InvalidPageError {
+        let message = format!("Page {} is not valid, the image has {} pages.", page, pages);
+        InvalidPageError { msg: message }
+    }
+
+    /// For images whose format has no pages.
+    pub fn single(page: usize) -> InvalidPageError {
+        let message = format!("Page {} is not valid, the image has a single page.", page);
+        InvalidPageError { msg: message }
+    }
+}
+
+impl fmt::Display for InvalidPageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl InvalidCropError {
+    pub fn new(crop: &Crop, width: i32, height: i32) -> InvalidCropError {
+        let message = format!(
+            "Crop {:?} is not valid for an image of {}x{}.",
+            crop, width, height
+        );
+        InvalidCropError { msg: message }
+    }
+}
+
+impl fmt::Display for InvalidCropError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
 impl fmt::Display for MagickError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.msg)
@@ -38,12 +132,36 @@ impl Error for InvalidSizeError {
     }
 }
 
+impl Error for InvalidKeyError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
 impl Error for MagickError {
     fn description(&self) -> &str {
         &self.msg
     }
 }
 
+impl Error for UnsupportedFormatError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl Error for InvalidPageError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
+impl Error for InvalidCropError {
+    fn description(&self) -> &str {
+        &self.msg
+    }
+}
+
 impl From<InvalidSizeError> for std::io::Error {
     fn from(error: InvalidSizeError) -> Self {
         std::io::Error::new(std::io::ErrorKind::InvalidData, error)
@@ -88,6 +206,33 @@ impl From<MagickError> for opencv::Error {
     }
 }
 
+impl From<UnsupportedFormatError> for opencv::Error {
+    fn from(error: UnsupportedFormatError) -> Self {
+        opencv::Error::new(UNSUPPORTED_FORMAT_CODE, error.msg)
+    }
+}
+
+impl From<InvalidPageError> for opencv::Error {
+    fn from(error: InvalidPageError) -> Self {
+        opencv::Error::new(INVALID_REQUEST_CODE, error.msg)
+    }
+}
+
+impl From<InvalidCropError> for opencv::Error {
+    fn from(error: InvalidCropError) -> Self {
+        opencv::Error::new(INVALID_REQUEST_CODE, error.msg)
+    }
+}
+
+/// Picks the status of a processing error from its code.
+pub fn into_http_error(error: opencv::Error) -> actix_web::Error {
+    match error.code {
+        INVALID_REQUEST_CODE => actix_web::error::ErrorBadRequest(error.message),
+        UNSUPPORTED_FORMAT_CODE => actix_web::error::ErrorUnsupportedMediaType(error.message),
+        _ => actix_web::error::ErrorInternalServerError(error),
+    }
+}
+
 impl From<MagickError> for actix_web::Error {
     fn from(error: MagickError) -> Self {
         actix_web::error::ErrorInternalServerError(error)
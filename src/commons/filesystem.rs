@@ -0,0 +1,194 @@
+use crate::commons::conditional::Validators;
+use crate::commons::source::{ImageFuture, ImageSource, SourceImage, ValidatorsFuture};
+use actix_web::error::BlockingError;
+use actix_web::http::header::HttpDate;
+use actix_web::web;
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures::future::Future;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+pub struct FilesystemSource {
+    root: PathBuf,
+}
+
+impl FilesystemSource {
+    pub fn new(root: &str) -> Self {
+        FilesystemSource {
+            root: PathBuf::from(root),
+        }
+    }
+
+    /// Reads the file of `key` under the root with `read`, on the blocking thread pool.
+    fn read<T, F>(&self, key: &str, read: F) -> Box<dyn Future<Item = T, Error = Error>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Path) -> io::Result<T> + Send + 'static,
+    {
+        if key.is_empty() || !is_relative_key(key) {
+            return Box::new(futures::failed(actix_web::error::ErrorNotFound(format!(
+                "File {} not found",
+                key
+            ))));
+        }
+        let root = self.root.clone();
+        let filename = key.to_string();
+        let resolved = filename.clone();
+        let block = web::block(move || read(&resolve(&root, &resolved)?));
+        Box::new(block.map_err(move |e| match e {
+            BlockingError::Error(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                actix_web::error::ErrorNotFound(format!("File {} not found", filename))
+            }
+            e => {
+                error!("Error reading file from disk: {:?}", e);
+                actix_web::error::ErrorInternalServerError(e)
+            }
+        }))
+    }
+}
+
+fn validators(path: &Path) -> io::Result<Validators> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|mtime| mtime.as_secs())
+        .unwrap_or(0);
+    Ok(Validators {
+        etag: Some(format!("\"{:x}-{:x}\"", mtime, metadata.len())),
+        last_modified: Some(HttpDate::from(modified).to_string()),
+    })
+}
+
+fn read_image(path: &Path) -> io::Result<SourceImage> {
+    let body = fs::read(path)?;
+    Ok(SourceImage {
+        validators: validators(path)?,
+        body: Bytes::from(body),
+    })
+}
+
+/// Path of the file of `key`, with symbolic links resolved. Files which end up outside of the
+/// root are reported as not found.
+fn resolve(root: &Path, key: &str) -> io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let path = root.join(key).canonicalize()?;
+    if path.starts_with(&root) {
+        Ok(path)
+    } else {
+        warn!("File {} resolves outside of {}", key, root.display());
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "File is outside of the root",
+        ))
+    }
+}
+
+fn is_relative_key(key: &str) -> bool {
+    Path::new(key).components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+impl ImageSource for FilesystemSource {
+    fn get_image(&self, key: &str) -> ImageFuture {
+        info!(
+            "Fetching image {} from directory: {}",
+            key,
+            self.root.display()
+        );
+        self.read(key, read_image)
+    }
+
+    fn get_validators(&self, key: &str) -> ValidatorsFuture {
+        self.read(key, validators)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_rt::System;
+    use actix_web::http::StatusCode;
+    use std::env;
+
+    fn fetch(source: &FilesystemSource, key: &str) -> Result<SourceImage, Error> {
+        System::new("test").block_on(futures::lazy(|| source.get_image(key)))
+    }
+
+    fn status_of(result: Result<SourceImage, Error>) -> StatusCode {
+        match result {
+            Ok(_) => panic!("Request should have failed"),
+            Err(e) => e.as_response_error().error_response().status(),
+        }
+    }
+
+    /// Empty directory under the temporary directory, removed before the test uses it.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustbier-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Unable to create temporary directory");
+        dir
+    }
+
+    #[test]
+    fn test_is_relative_key() {
+        assert!(is_relative_key("img-test"));
+        assert!(is_relative_key("listings/2024/abc.jpg"));
+        assert!(!is_relative_key(".."));
+        assert!(!is_relative_key("../abc.jpg"));
+        assert!(!is_relative_key("listings/../../abc.jpg"));
+        assert!(!is_relative_key("/etc/passwd"));
+    }
+
+    #[test]
+    fn test_get_image() {
+        let source = FilesystemSource::new("tests/resources");
+        let image = fetch(&source, "img-test").expect("Unable to read image");
+        assert_eq!(
+            image.body,
+            Bytes::from(fs::read("tests/resources/img-test").unwrap())
+        );
+        assert!(image.validators.etag.is_some());
+        assert!(image.validators.last_modified.is_some());
+    }
+
+    #[test]
+    fn test_get_invalid_key() {
+        let source = FilesystemSource::new("tests/resources");
+        assert_eq!(status_of(fetch(&source, "")), StatusCode::NOT_FOUND);
+        assert_eq!(status_of(fetch(&source, "..")), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status_of(fetch(&source, "../resources/img-test")),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status_of(fetch(&source, "/etc/passwd")),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status_of(fetch(&source, "missing")), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_outside_root() {
+        let dir = temp_dir("symlink");
+        let root = dir.join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(dir.join("secret"), b"secret").unwrap();
+        fs::write(root.join("inside"), b"inside").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("outside")).unwrap();
+        std::os::unix::fs::symlink(root.join("inside"), root.join("link")).unwrap();
+        let source = FilesystemSource::new(root.to_str().unwrap());
+        assert_eq!(status_of(fetch(&source, "outside")), StatusCode::NOT_FOUND);
+        assert_eq!(
+            fetch(&source, "link").expect("Unable to read image").body,
+            Bytes::from_static(b"inside")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,700 @@
+use crate::commons::errors::{InvalidCropError, InvalidSizeError};
+use crate::commons::{
+    get_scaled_size, get_target_size, Crop, CropUnit, Filter, Fit, Focus, Gravity,
+    ProcessImageRequest, Size,
+};
+
+/// A rectangle, in pixels.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// How an image is resized, from the parameters of the request and the configured limits.
+#[derive(Debug)]
+pub struct Resize {
+    /// Desired size, multiplied by the device pixel ratio.
+    pub size: Size,
+    pub fit: Fit,
+    pub focus: Focus,
+    /// Whether `Cover` crops are placed by the content of the image instead of by `focus`.
+    pub smart: bool,
+    pub upscale: bool,
+    pub max_pixels: u64,
+    pub filter: Option<Filter>,
+}
+
+impl Resize {
+    pub fn new(request: &ProcessImageRequest, max_pixels: u64) -> Resize {
+        let dpr = request.dpr.unwrap_or(1.0);
+        let scale = |measure: Option<i32>| {
+            measure.map(|measure| {
+                (f64::from(measure) * dpr)
+                    .round()
+                    .min(f64::from(i32::max_value())) as i32
+            })
+        };
+        Resize {
+            size: Size {
+                width: scale(request.size.width),
+                height: scale(request.size.height),
+            },
+            fit: request.fit,
+            focus: request.focus(),
+            smart: request.gravity == Gravity::Smart && request.focus.is_none(),
+            upscale: request.upscale,
+            max_pixels,
+            filter: request.filter,
+        }
+    }
+}
+
+/// Where the pixels of the original image end up in the output.
+#[derive(Debug, PartialEq)]
+pub struct Layout {
+    /// Size the whole image is resized to.
+    pub width: i32,
+    pub height: i32,
+    /// Part of the resized image which is kept.
+    pub crop: Option<Region>,
+    /// Canvas the resized image is placed on, with the position of the image on it.
+    pub pad: Option<Region>,
+}
+
+impl Layout {
+    fn resize(width: i32, height: i32) -> Layout {
+        Layout {
+            width,
+            height,
+            crop: None,
+            pad: None,
+        }
+    }
+
+    /// Size of the image once laid out.
+    pub fn output_size(&self) -> (i32, i32) {
+        match (&self.pad, &self.crop) {
+            (Some(canvas), _) => (canvas.width, canvas.height),
+            (None, Some(crop)) => (crop.width, crop.height),
+            (None, None) => (self.width, self.height),
+        }
+    }
+}
+
+/// Lays the image out following `resize`. Layouts with more than `max_pixels` pixels, either in
+/// the output or in the resized image a crop is taken from, are laid out again in a
+/// proportionally smaller size.
+pub fn get_layout(
+    original_width: i32,
+    original_height: i32,
+    resize: &Resize,
+) -> Result<Layout, InvalidSizeError> {
+    let mut layout = fit_layout(original_width, original_height, &resize.size, resize)?;
+    loop {
+        let (width, height) = layout.output_size();
+        let pixels = (width as u64 * height as u64).max(layout.width as u64 * layout.height as u64);
+        if pixels <= resize.max_pixels {
+            return Ok(layout);
+        }
+        let ratio = (resize.max_pixels as f64 / pixels as f64).sqrt();
+        let scale = |measure: i32| ((f64::from(measure) * ratio).floor() as i32).max(1);
+        let (limited_width, limited_height) = (scale(width), scale(height));
+        if (limited_width, limited_height) == (width, height) {
+            // Without upscaling the resized image is never larger than the original.
+            return Ok(layout);
+        }
+        debug!(
+            "Limiting the output size from {}x{} to {}x{}",
+            width, height, limited_width, limited_height
+        );
+        let limited_size = Size {
+            width: Some(limited_width),
+            height: Some(limited_height),
+        };
+        layout = fit_layout(original_width, original_height, &limited_size, resize)?;
+    }
+}
+
+/// Lays the image out in the desired size following the fit of `resize`. Unless it allows
+/// upscaling, images are never enlarged: `Fill` produces smaller images than the desired size
+/// when the original is smaller and `Cover` crops the largest part of the desired aspect ratio
+/// it holds, while `Pad` always produces the desired size.
+/// Without both a width and a height, every mode behaves like `Contain`. `Cover` keeps the
+/// focus as close to the centre of its crop as the edges of the image allow.
+fn fit_layout(
+    original_width: i32,
+    original_height: i32,
+    desired_size: &Size,
+    resize: &Resize,
+) -> Result<Layout, InvalidSizeError> {
+    let contain = |desired_size: &Size| {
+        if resize.upscale {
+            get_scaled_size(original_width, original_height, desired_size, Fit::Contain)
+        } else {
+            get_target_size(original_width, original_height, desired_size)
+        }
+    };
+    let (width, height) = match (resize.fit, desired_size.width, desired_size.height) {
+        (Fit::Contain, _, _) | (_, None, _) | (_, _, None) => {
+            let (width, height) = contain(desired_size)?;
+            return Ok(Layout::resize(width, height));
+        }
+        (_, Some(width), Some(height)) if width <= 0 || height <= 0 => {
+            return Err(InvalidSizeError::new(desired_size));
+        }
+        (_, Some(width), Some(height)) => (width, height),
+    };
+    match resize.fit {
+        Fit::Fill if resize.upscale => Ok(Layout::resize(width, height)),
+        Fit::Fill => Ok(Layout::resize(
+            width.min(original_width),
+            height.min(original_height),
+        )),
+        Fit::Cover => {
+            let ratio =
+                (width as f32 / original_width as f32).max(height as f32 / original_height as f32);
+            let ratio = if resize.upscale {
+                ratio
+            } else {
+                ratio.min(1.0)
+            };
+            let scale = |measure: i32| ((measure as f32 * ratio).round() as i32).max(1);
+            let (resized_width, resized_height) = (scale(original_width), scale(original_height));
+            // Small originals keep the aspect ratio of the desired size in a smaller crop.
+            let shrink = (resized_width as f32 / width as f32)
+                .min(resized_height as f32 / height as f32)
+                .min(1.0);
+            let shrunk = |measure: i32, resized: i32| {
+                ((measure as f32 * shrink).round() as i32)
+                    .max(1)
+                    .min(resized)
+            };
+            let (crop_width, crop_height) =
+                (shrunk(width, resized_width), shrunk(height, resized_height));
+            let offset = |focus: f64, resized: i32, cropped: i32| {
+                let centred = (focus * f64::from(resized) - f64::from(cropped) / 2.0).floor();
+                (centred as i32).max(0).min(resized - cropped)
+            };
+            let crop = Region {
+                x: offset(resize.focus.x, resized_width, crop_width),
+                y: offset(resize.focus.y, resized_height, crop_height),
+                width: crop_width,
+                height: crop_height,
+            };
+            let mut layout = Layout::resize(resized_width, resized_height);
+            if (crop.width, crop.height) != (resized_width, resized_height) {
+                layout.crop = Some(crop);
+            }
+            Ok(layout)
+        }
+        Fit::Contain | Fit::Pad => {
+            let (resized_width, resized_height) = contain(desired_size)?;
+            let mut layout = Layout::resize(resized_width, resized_height);
+            if (resized_width, resized_height) != (width, height) {
+                layout.pad = Some(Region {
+                    x: (width - resized_width) / 2,
+                    y: (height - resized_height) / 2,
+                    width,
+                    height,
+                });
+            }
+            Ok(layout)
+        }
+    }
+}
+
+/// Scales `width`x`height` down, keeping its aspect ratio, until it has at most `max_pixels`
+/// pixels.
+pub fn limit_pixels(width: i32, height: i32, max_pixels: u64) -> (i32, i32) {
+    let pixels = width as u64 * height as u64;
+    if pixels <= max_pixels {
+        return (width, height);
+    }
+    let ratio = (max_pixels as f64 / pixels as f64).sqrt();
+    let scale = |measure: i32| ((f64::from(measure) * ratio).floor() as i32).max(1);
+    (scale(width), scale(height))
+}
+
+/// Affine transformation rotating an image of `width`x`height` counter-clockwise by `degrees`
+/// around its centre, as the 2x3 matrix OpenCV takes row by row, and the size of the rotated
+/// image. With `expand` the image grows to hold all of the rotated pixels, scaled down when
+/// that takes more than `max_pixels` pixels, otherwise it keeps its size and the corners are
+/// cut off.
+pub fn get_rotation(
+    width: i32,
+    height: i32,
+    degrees: f64,
+    expand: bool,
+    max_pixels: u64,
+) -> ([f64; 6], (i32, i32)) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (rotated_width, rotated_height, scale) = if expand {
+        let (width, height) = (f64::from(width), f64::from(height));
+        let (rotated_width, rotated_height) = (
+            (width * cos.abs() + height * sin.abs()).round() as i32,
+            (width * sin.abs() + height * cos.abs()).round() as i32,
+        );
+        let (limited_width, limited_height) =
+            limit_pixels(rotated_width, rotated_height, max_pixels);
+        let scale = (f64::from(limited_width) / f64::from(rotated_width))
+            .min(f64::from(limited_height) / f64::from(rotated_height));
+        (limited_width, limited_height, scale)
+    } else {
+        (width, height, 1.0)
+    };
+    let (sin, cos) = (sin * scale, cos * scale);
+    // Centres of the pixel grids, so right angles map pixels exactly onto pixels.
+    let centre = |measure: i32| f64::from(measure - 1) / 2.0;
+    let (x, y) = (centre(width), centre(height));
+    let (rotated_x, rotated_y) = (centre(rotated_width), centre(rotated_height));
+    let matrix = [
+        cos,
+        sin,
+        rotated_x - cos * x - sin * y,
+        -sin,
+        cos,
+        rotated_y + sin * x - cos * y,
+    ];
+    (matrix, (rotated_width, rotated_height))
+}
+
+/// Start of the window of `length` consecutive values with the highest sum. Ties go to the
+/// window closest to the centre, so that values without any peak give a centred window.
+pub fn best_window(values: &[u64], length: usize) -> usize {
+    if length >= values.len() {
+        return 0;
+    }
+    let last = values.len() - length;
+    let distance = |start: usize| (start as i64 - (last / 2) as i64).abs();
+    let mut sum: u64 = values[..length].iter().sum();
+    let mut best = (sum, 0);
+    for start in 1..=last {
+        sum = sum + values[start + length - 1] - values[start - 1];
+        if sum > best.0 || (sum == best.0 && distance(start) < distance(best.1)) {
+            best = (sum, start);
+        }
+    }
+    best.1
+}
+
+/// Converts the crop of a request to pixels of an image of `width`x`height`, checking it fits
+/// in the image.
+pub fn get_crop_region(crop: &Crop, width: i32, height: i32) -> Result<Region, InvalidCropError> {
+    let (scale_x, scale_y) = match crop.unit {
+        CropUnit::Pixels => (1.0, 1.0),
+        CropUnit::Relative => (f64::from(width), f64::from(height)),
+    };
+    let x = (crop.x * scale_x).round();
+    let y = (crop.y * scale_y).round();
+    let mut region_width = (crop.width * scale_x).round();
+    let mut region_height = (crop.height * scale_y).round();
+    if crop.unit == CropUnit::Relative {
+        // Rounding can push a rectangle reaching the edge of the image a pixel past it.
+        region_width = region_width.min(f64::from(width) - x);
+        region_height = region_height.min(f64::from(height) - y);
+    }
+    let fits = x >= 0.0
+        && y >= 0.0
+        && region_width >= 1.0
+        && region_height >= 1.0
+        && x + region_width <= f64::from(width)
+        && y + region_height <= f64::from(height);
+    if !fits {
+        return Err(InvalidCropError::new(crop, width, height));
+    }
+    Ok(Region {
+        x: x as i32,
+        y: y as i32,
+        width: region_width as i32,
+        height: region_height as i32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resize(width: i32, height: i32, fit: Fit) -> Resize {
+        Resize {
+            size: Size {
+                width: Some(width),
+                height: Some(height),
+            },
+            fit,
+            focus: Focus::default(),
+            smart: false,
+            upscale: false,
+            max_pixels: u64::max_value(),
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn test_contain_layout() {
+        assert_eq!(
+            get_layout(400, 300, &resize(300, 300, Fit::Contain)),
+            Ok(Layout::resize(300, 225))
+        );
+        assert_eq!(
+            get_layout(
+                400,
+                300,
+                &Resize {
+                    size: Size {
+                        width: Some(200),
+                        height: None
+                    },
+                    ..resize(0, 0, Fit::Cover)
+                }
+            ),
+            Ok(Layout::resize(200, 150))
+        );
+    }
+
+    #[test]
+    fn test_cover_layout() {
+        assert_eq!(
+            get_layout(400, 300, &resize(300, 300, Fit::Cover)),
+            Ok(Layout {
+                width: 400,
+                height: 300,
+                crop: Some(Region {
+                    x: 50,
+                    y: 0,
+                    width: 300,
+                    height: 300
+                }),
+                pad: None
+            })
+        );
+        assert_eq!(
+            get_layout(300, 600, &resize(100, 100, Fit::Cover)),
+            Ok(Layout {
+                width: 100,
+                height: 200,
+                crop: Some(Region {
+                    x: 0,
+                    y: 50,
+                    width: 100,
+                    height: 100
+                }),
+                pad: None
+            })
+        );
+        assert_eq!(
+            get_layout(400, 300, &resize(200, 150, Fit::Cover)),
+            Ok(Layout::resize(200, 150))
+        );
+        assert_eq!(
+            get_layout(100, 50, &resize(200, 200, Fit::Cover)),
+            Ok(Layout {
+                width: 100,
+                height: 50,
+                crop: Some(Region {
+                    x: 25,
+                    y: 0,
+                    width: 50,
+                    height: 50
+                }),
+                pad: None
+            })
+        );
+        assert_eq!(
+            get_layout(100, 50, &resize(400, 100, Fit::Cover)),
+            Ok(Layout {
+                width: 100,
+                height: 50,
+                crop: Some(Region {
+                    x: 0,
+                    y: 12,
+                    width: 100,
+                    height: 25
+                }),
+                pad: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_fill_layout() {
+        assert_eq!(
+            get_layout(400, 300, &resize(300, 300, Fit::Fill)),
+            Ok(Layout::resize(300, 300))
+        );
+        assert_eq!(
+            get_layout(400, 300, &resize(500, 100, Fit::Fill)),
+            Ok(Layout::resize(400, 100))
+        );
+    }
+
+    #[test]
+    fn test_pad_layout() {
+        assert_eq!(
+            get_layout(400, 300, &resize(300, 300, Fit::Pad)),
+            Ok(Layout {
+                width: 300,
+                height: 225,
+                crop: None,
+                pad: Some(Region {
+                    x: 0,
+                    y: 37,
+                    width: 300,
+                    height: 300
+                })
+            })
+        );
+        assert_eq!(
+            get_layout(100, 50, &resize(200, 200, Fit::Pad)),
+            Ok(Layout {
+                width: 100,
+                height: 50,
+                crop: None,
+                pad: Some(Region {
+                    x: 50,
+                    y: 75,
+                    width: 200,
+                    height: 200
+                })
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_layout() {
+        assert!(get_layout(400, 300, &resize(0, 300, Fit::Cover)).is_err());
+        assert!(get_layout(400, 300, &resize(300, -1, Fit::Pad)).is_err());
+    }
+
+    fn crop(x: f64, y: f64, width: f64, height: f64, unit: CropUnit) -> Crop {
+        Crop {
+            x,
+            y,
+            width,
+            height,
+            unit,
+        }
+    }
+
+    #[test]
+    fn test_crop_region() {
+        assert_eq!(
+            get_crop_region(&crop(10.0, 20.0, 100.0, 50.0, CropUnit::Pixels), 400, 300),
+            Ok(Region {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 50
+            })
+        );
+        assert_eq!(
+            get_crop_region(&crop(0.25, 0.5, 0.5, 0.5, CropUnit::Relative), 400, 300),
+            Ok(Region {
+                x: 100,
+                y: 150,
+                width: 200,
+                height: 150
+            })
+        );
+        assert_eq!(
+            get_crop_region(&crop(0.5, 0.5, 0.5, 0.5, CropUnit::Relative), 401, 301),
+            Ok(Region {
+                x: 201,
+                y: 151,
+                width: 200,
+                height: 150
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_crop_region() {
+        assert!(
+            get_crop_region(&crop(350.0, 0.0, 100.0, 50.0, CropUnit::Pixels), 400, 300).is_err()
+        );
+        assert!(
+            get_crop_region(&crop(0.0, 0.0, 400.0, 301.0, CropUnit::Pixels), 400, 300).is_err()
+        );
+        assert!(
+            get_crop_region(&crop(0.0, 0.0, 0.001, 0.5, CropUnit::Relative), 400, 300).is_err()
+        );
+        assert!(get_crop_region(
+            &crop(std::f64::NAN, 0.0, 10.0, 10.0, CropUnit::Pixels),
+            400,
+            300
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_cover_layout_focus() {
+        let crop_x = |focus: Focus| {
+            get_layout(
+                400,
+                300,
+                &Resize {
+                    focus,
+                    ..resize(300, 300, Fit::Cover)
+                },
+            )
+            .ok()
+            .and_then(|layout| layout.crop)
+            .map(|crop| crop.x)
+        };
+        assert_eq!(crop_x(Focus { x: 0.0, y: 0.5 }), Some(0));
+        assert_eq!(crop_x(Focus { x: 1.0, y: 0.5 }), Some(100));
+        assert_eq!(crop_x(Focus { x: 0.6, y: 0.0 }), Some(90));
+        assert_eq!(crop_x(Focus { x: 0.3, y: 1.0 }), Some(0));
+        assert_eq!(
+            get_layout(
+                300,
+                600,
+                &Resize {
+                    focus: Focus { x: 0.5, y: 0.0 },
+                    ..resize(100, 100, Fit::Cover)
+                }
+            )
+            .map(|layout| layout.crop),
+            Ok(Some(Region {
+                x: 0,
+                y: 0,
+                width: 100,
+                height: 100
+            }))
+        );
+    }
+
+    #[test]
+    fn test_upscaled_layout() {
+        let upscaled = |width: i32, height: i32, fit: Fit| Resize {
+            upscale: true,
+            ..resize(width, height, fit)
+        };
+        assert_eq!(
+            get_layout(100, 50, &upscaled(300, 300, Fit::Contain)),
+            Ok(Layout::resize(300, 150))
+        );
+        assert_eq!(
+            get_layout(100, 50, &upscaled(300, 300, Fit::Fill)),
+            Ok(Layout::resize(300, 300))
+        );
+        assert_eq!(
+            get_layout(100, 50, &upscaled(300, 300, Fit::Cover)),
+            Ok(Layout {
+                width: 600,
+                height: 300,
+                crop: Some(Region {
+                    x: 150,
+                    y: 0,
+                    width: 300,
+                    height: 300
+                }),
+                pad: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_limited_layout() {
+        let limited = |width: i32, height: i32, fit: Fit| Resize {
+            upscale: true,
+            max_pixels: 10_000,
+            ..resize(width, height, fit)
+        };
+        assert_eq!(
+            get_layout(400, 300, &limited(300, 300, Fit::Contain)),
+            Ok(Layout::resize(115, 86))
+        );
+        assert_eq!(
+            get_layout(400, 300, &limited(300, 300, Fit::Pad)).map(|layout| layout.output_size()),
+            Ok((100, 100))
+        );
+        assert_eq!(
+            get_layout(400, 300, &limited(1000, 1000, Fit::Cover))
+                .map(|layout| layout.output_size()),
+            Ok((100, 100))
+        );
+        let layout = get_layout(
+            1,
+            1000,
+            &Resize {
+                max_pixels: 50_000_000,
+                ..limited(4000, 4000, Fit::Cover)
+            },
+        )
+        .expect("Invalid layout");
+        assert!(layout.width as u64 * layout.height as u64 <= 50_000_000);
+        assert_eq!((layout.width, layout.height), (223, 223_000));
+        assert_eq!(layout.output_size(), (223, 223));
+    }
+
+    #[test]
+    fn test_resize_dpr() {
+        let request = serde_qs::from_str::<ProcessImageRequest>("size[width]=150&dpr=2.5")
+            .expect("Invalid query string");
+        let resize = Resize::new(&request, 1000);
+        assert_eq!(resize.size.width, Some(375));
+        assert_eq!(resize.size.height, None);
+        assert_eq!(resize.max_pixels, 1000);
+    }
+
+    #[test]
+    fn test_limit_pixels() {
+        assert_eq!(limit_pixels(100, 100, 10_000), (100, 100));
+        assert_eq!(limit_pixels(200, 100, 5_000), (100, 50));
+        assert_eq!(limit_pixels(300, 200, 600), (30, 20));
+    }
+
+    #[test]
+    fn test_best_window() {
+        assert_eq!(best_window(&[0, 0, 0, 0, 0], 3), 1);
+        assert_eq!(best_window(&[9, 1, 0, 0, 0, 0], 2), 0);
+        assert_eq!(best_window(&[0, 0, 0, 5, 5, 1], 2), 3);
+        assert_eq!(best_window(&[0, 0, 0, 1, 0, 0, 0, 0], 3), 3);
+        assert_eq!(best_window(&[1, 2, 3], 3), 0);
+        assert_eq!(best_window(&[1, 2], 5), 0);
+    }
+
+    fn rounded_rotation(
+        width: i32,
+        height: i32,
+        degrees: f64,
+        expand: bool,
+    ) -> (Vec<i64>, (i32, i32)) {
+        let (matrix, size) = get_rotation(width, height, degrees, expand, u64::max_value());
+        let matrix = matrix
+            .iter()
+            .map(|value| (value * 1000.0).round() as i64)
+            .collect();
+        (matrix, size)
+    }
+
+    #[test]
+    fn test_rotation() {
+        // The top left corner of a 4x2 image ends up in the bottom left corner.
+        assert_eq!(
+            rounded_rotation(4, 2, 90.0, true),
+            (vec![0, 1000, 0, -1000, 0, 3000], (2, 4))
+        );
+        assert_eq!(
+            rounded_rotation(4, 2, 90.0, false),
+            (vec![0, 1000, 1000, -1000, 0, 2000], (4, 2))
+        );
+        assert_eq!(
+            rounded_rotation(4, 2, 180.0, true),
+            (vec![-1000, 0, 3000, 0, -1000, 1000], (4, 2))
+        );
+        assert_eq!(rounded_rotation(100, 100, 45.0, true).1, (141, 141));
+        assert_eq!(rounded_rotation(100, 50, -3.0, false).1, (100, 50));
+    }
+
+    #[test]
+    fn test_limited_rotation() {
+        let (matrix, size) = get_rotation(100, 100, 45.0, true, 12_000);
+        assert_eq!(size, (109, 109));
+        assert!((matrix[0].hypot(matrix[1]) - 109.0 / 141.0).abs() < 1e-9);
+        assert_eq!(get_rotation(100, 100, 45.0, false, 5_000).1, (100, 100));
+    }
+}
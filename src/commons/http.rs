@@ -0,0 +1,262 @@
+use crate::commons::conditional::Validators;
+use crate::commons::source::{ImageFuture, ImageSource, SourceImage, ValidatorsFuture};
+use actix_web::client::Client;
+use actix_web::http::{header, HeaderMap, StatusCode, Uri};
+use actix_web::Error;
+use futures::future::{Either, Future};
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use std::time::Duration;
+use tokio_timer::Timeout;
+
+thread_local! {
+    // awc clients are not `Send`, so sources share one per worker thread. Its own timeout only
+    // bounds `send()`, so it is disabled in favour of `with_timeout`.
+    static CLIENT: Client = Client::build().disable_timeout().finish();
+}
+
+pub struct HttpSource {
+    base_url: String,
+    allowed_hosts: Vec<String>,
+    timeout: Duration,
+    max_body_size: usize,
+}
+
+impl HttpSource {
+    pub fn new(
+        base_url: &str,
+        allowed_hosts: &[String],
+        timeout_ms: u64,
+        max_body_size: usize,
+    ) -> Self {
+        HttpSource {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            allowed_hosts: allowed_hosts.to_vec(),
+            timeout: Duration::from_millis(timeout_ms),
+            max_body_size,
+        }
+    }
+
+    fn get_url(&self, key: &str) -> Result<String, Error> {
+        let path = key
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT_ENCODE_SET).to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        let url = format!("{}/{}", self.base_url, path);
+        let uri = url
+            .parse::<Uri>()
+            .map_err(actix_web::error::ErrorBadRequest)?;
+        match uri.host() {
+            Some(host) if self.allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) => {
+                Ok(url)
+            }
+            host => {
+                error!("Host {:?} is not in the HTTP origin allowlist", host);
+                Err(actix_web::error::ErrorForbidden(format!(
+                    "File {} is not available",
+                    key
+                )))
+            }
+        }
+    }
+}
+
+/// Bounds the whole exchange with the origin, reading the body included.
+fn with_timeout<F>(
+    future: F,
+    timeout: Duration,
+    filename: String,
+) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+{
+    Timeout::new(future, timeout).map_err(move |e| {
+        if e.is_elapsed() {
+            error!("HTTP origin timed out for {}", filename);
+            return actix_web::error::ErrorGatewayTimeout(format!(
+                "HTTP origin timed out for {}",
+                filename
+            ));
+        }
+        e.into_inner().unwrap_or_else(|| {
+            actix_web::error::ErrorInternalServerError("Unable to time the HTTP origin")
+        })
+    })
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+impl ImageSource for HttpSource {
+    fn get_image(&self, key: &str) -> ImageFuture {
+        let url = match self.get_url(key) {
+            Ok(url) => url,
+            Err(e) => return Box::new(futures::failed(e)),
+        };
+        info!("Fetching image {} from HTTP origin: {}", key, url);
+        let filename = key.to_string();
+        let max_body_size = self.max_body_size;
+        let exchange = CLIENT.with(|client| {
+            client
+                .get(url)
+                .send()
+                .map_err(|e| {
+                    error!("Error fetching file from HTTP origin: {:?}", e);
+                    actix_web::error::ErrorBadGateway(e)
+                })
+                .and_then(move |mut res| match res.status() {
+                    status if status.is_success() => {
+                        let validators = Validators {
+                            etag: header_value(res.headers(), header::ETAG),
+                            last_modified: header_value(res.headers(), header::LAST_MODIFIED),
+                        };
+                        Either::A(
+                            res.body()
+                                .limit(max_body_size)
+                                .map(move |body| SourceImage { body, validators })
+                                .map_err(|e| {
+                                    error!("Error reading file from HTTP origin: {:?}", e);
+                                    actix_web::error::ErrorBadGateway(e)
+                                }),
+                        )
+                    }
+                    StatusCode::NOT_FOUND => Either::B(futures::failed(
+                        actix_web::error::ErrorNotFound(format!("File {} not found", filename)),
+                    )),
+                    status => {
+                        error!("HTTP origin responded with {} for {}", status, filename);
+                        Either::B(futures::failed(actix_web::error::ErrorBadGateway(
+                            format!("HTTP origin responded with {}", status),
+                        )))
+                    }
+                })
+        });
+        Box::new(with_timeout(exchange, self.timeout, key.to_string()))
+    }
+
+    fn get_validators(&self, key: &str) -> ValidatorsFuture {
+        let url = match self.get_url(key) {
+            Ok(url) => url,
+            Err(e) => return Box::new(futures::failed(e)),
+        };
+        debug!("Fetching validators of {} from HTTP origin: {}", key, url);
+        let filename = key.to_string();
+        let exchange = CLIENT.with(|client| {
+            client
+                .head(url)
+                .send()
+                .map_err(|e| {
+                    error!("Error fetching file headers from HTTP origin: {:?}", e);
+                    actix_web::error::ErrorBadGateway(e)
+                })
+                .and_then(move |res| match res.status() {
+                    status if status.is_success() => Ok(Validators {
+                        etag: header_value(res.headers(), header::ETAG),
+                        last_modified: header_value(res.headers(), header::LAST_MODIFIED),
+                    }),
+                    StatusCode::NOT_FOUND => Err(actix_web::error::ErrorNotFound(format!(
+                        "File {} not found",
+                        filename
+                    ))),
+                    status => Err(actix_web::error::ErrorBadGateway(format!(
+                        "HTTP origin responded with {}",
+                        status
+                    ))),
+                })
+        });
+        Box::new(with_timeout(exchange, self.timeout, key.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commons::stub;
+    use actix_rt::System;
+    use actix_web::web::Bytes;
+
+    fn fetch(source: &HttpSource, key: &str) -> Result<Bytes, Error> {
+        System::new("test").block_on(futures::lazy(|| source.get_image(key).map(|i| i.body)))
+    }
+
+    fn status_of(result: Result<Bytes, Error>) -> StatusCode {
+        result
+            .expect_err("Request should have failed")
+            .as_response_error()
+            .error_response()
+            .status()
+    }
+
+    #[test]
+    fn test_fetch_from_origin() {
+        let (base_url, _) = stub::serve("200 OK", "", b"image-bytes");
+        let source = HttpSource::new(&base_url, &["127.0.0.1".to_string()], 1000, 1024);
+        assert_eq!(
+            fetch(&source, "img-test").expect("Unable to fetch image"),
+            Bytes::from_static(b"image-bytes")
+        );
+    }
+
+    #[test]
+    fn test_origin_not_found() {
+        let (base_url, _) = stub::serve("404 Not Found", "", b"");
+        let source = HttpSource::new(&base_url, &["127.0.0.1".to_string()], 1000, 1024);
+        assert_eq!(status_of(fetch(&source, "missing")), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_origin_validators_not_found() {
+        let (base_url, _) = stub::serve("404 Not Found", "", b"");
+        let source = HttpSource::new(&base_url, &["127.0.0.1".to_string()], 1000, 1024);
+        let result = System::new("test")
+            .block_on(futures::lazy(|| source.get_validators("missing")))
+            .map(|_| Bytes::new());
+        assert_eq!(status_of(result), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_origin_body_too_large() {
+        let (base_url, _) = stub::serve("200 OK", "", b"image-bytes");
+        let source = HttpSource::new(&base_url, &["127.0.0.1".to_string()], 1000, 4);
+        assert_eq!(status_of(fetch(&source, "img-test")), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_origin_body_timeout() {
+        let (base_url, _) =
+            stub::serve_delayed("200 OK", "", b"image-bytes", Duration::from_secs(2));
+        let source = HttpSource::new(&base_url, &["127.0.0.1".to_string()], 200, 1024);
+        assert_eq!(status_of(fetch(&source, "img-test")), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_fetch_nested_key_from_origin() {
+        let (base_url, _) = stub::serve("200 OK", "", b"image-bytes");
+        let source = HttpSource::new(&base_url, &["127.0.0.1".to_string()], 1000, 1024);
+        assert_eq!(
+            source
+                .get_url("listings/2024/my image.jpg")
+                .expect("Unable to build URL"),
+            format!("{}/listings/2024/my%20image.jpg", base_url)
+        );
+        assert_eq!(
+            fetch(&source, "listings/2024/my image.jpg").expect("Unable to fetch image"),
+            Bytes::from_static(b"image-bytes")
+        );
+    }
+
+    #[test]
+    fn test_host_not_allowed() {
+        let source = HttpSource::new(
+            "http://cdn.example.com",
+            &["assets.example.com".to_string()],
+            1000,
+            1024,
+        );
+        assert_eq!(status_of(fetch(&source, "img-test")), StatusCode::FORBIDDEN);
+    }
+}
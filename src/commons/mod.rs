@@ -1,11 +1,30 @@
+pub mod conditional;
 pub mod errors;
+pub mod filesystem;
+pub mod geometry;
+pub mod http;
+pub mod negotiation;
 pub mod s3;
+pub mod source;
+#[cfg(test)]
+pub mod stub;
 
+use actix_web::http::{HeaderValue, StatusCode};
 use config::{Config, ConfigError, File};
 use errors::InvalidSizeError;
 use rusoto_core::Region;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
 use std::fmt;
+use std::sync::Arc;
+
+pub const MAX_AVIF_SPEED: u8 = 9;
+/// Tolerance of the checks on relative coordinates, whose sums are rarely exact.
+const RELATIVE_EPSILON: f64 = 1e-6;
+pub const MAX_DPR: f64 = 4.0;
+
+pub const BUILTIN_SRGB_PROFILE: &[u8] = include_bytes!("srgb.icc");
 
 #[derive(Serialize, Deserialize)]
 #[serde(remote = "Region")]
@@ -37,11 +56,93 @@ pub enum RegionDef {
 #[derive(Debug, Deserialize)]
 pub struct Configuration {
     pub png_quality: u8,
+    #[serde(default = "default_avif_speed")]
+    pub avif_speed: u8,
+    #[serde(default)]
+    pub jpeg: JpegOptions,
+    #[serde(default = "default_max_output_pixels")]
+    pub max_output_pixels: u64,
+    #[serde(default)]
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub icc: Icc,
+    pub srgb_profile: Option<String>,
+    #[serde(skip)]
+    pub srgb: Option<Arc<Vec<u8>>>,
+    pub sources: HashMap<String, SourceConfig>,
+    pub default_source: Option<String>,
+    pub watermark_source: Option<String>,
+    pub app_port: u16,
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub cache_control: CacheControlConfig,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CacheConfig {
+    pub memory: Option<MemoryCacheConfig>,
+    pub disk: Option<DiskCacheConfig>,
+    pub derivatives: Option<DerivativesConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemoryCacheConfig {
+    pub max_bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DerivativesConfig {
     #[serde(with = "RegionDef")]
     pub region: Region,
     pub bucket: String,
-    pub app_port: u16,
-    pub log_level: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiskCacheConfig {
+    pub directory: String,
+    pub max_bytes: u64,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CacheControlRules {
+    pub success: Option<String>,
+    pub not_found: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CacheControlConfig {
+    #[serde(flatten)]
+    pub default: CacheControlRules,
+    #[serde(default)]
+    pub sources: HashMap<String, CacheControlRules>,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum SourceConfig {
+    S3 {
+        #[serde(with = "RegionDef")]
+        region: Region,
+        bucket: String,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+    },
+    Filesystem {
+        root: String,
+    },
+    Http {
+        base_url: String,
+        allowed_hosts: Vec<String>,
+        #[serde(default = "default_http_timeout_ms")]
+        timeout_ms: u64,
+        #[serde(default = "default_http_max_body_size")]
+        max_body_size: usize,
+    },
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,6 +157,146 @@ pub struct ProcessImageRequest {
     pub watermarks: Vec<Watermark>,
     #[serde(default)]
     pub rotation: Option<Rotation>,
+    #[serde(default)]
+    pub speed: Option<u8>,
+    #[serde(default)]
+    pub page: usize,
+    #[serde(default)]
+    pub webp: WebpOptions,
+    #[serde(default)]
+    pub jpeg: JpegOptions,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub background: Color,
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    #[serde(default)]
+    pub gravity: Gravity,
+    #[serde(default)]
+    pub focus: Option<Focus>,
+    #[serde(default)]
+    pub upscale: bool,
+    #[serde(default)]
+    pub dpr: Option<f64>,
+    #[serde(default)]
+    pub filter: Option<Filter>,
+    #[serde(default = "default_auto_orient")]
+    pub auto_orient: bool,
+    /// Counter-clockwise rotation in degrees, applied after `rotation`.
+    #[serde(default)]
+    pub angle: Option<f64>,
+    #[serde(default)]
+    pub expand: bool,
+    #[serde(default)]
+    pub flip: bool,
+    #[serde(default)]
+    pub flop: bool,
+    #[serde(default)]
+    pub metadata: Option<Metadata>,
+    #[serde(default)]
+    pub icc: Option<Icc>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Linear,
+    Cubic,
+    Area,
+    Lanczos,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Gravity {
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+    Smart,
+}
+
+/// Relative to the size of the image, from 0 to 1.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct Focus {
+    pub x: f64,
+    pub y: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Crop {
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub unit: CropUnit,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum CropUnit {
+    Pixels,
+    Relative,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Fit {
+    Contain,
+    Cover,
+    Fill,
+    Pad,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Metadata {
+    StripAll,
+    KeepIcc,
+    KeepCopyright,
+}
+
+/// CMYK images are converted to sRGB either way.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Icc {
+    Convert,
+    Preserve,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct JpegOptions {
+    pub progressive: Option<bool>,
+    pub optimize: Option<bool>,
+    pub subsampling: Option<Subsampling>,
+    pub restart_interval: Option<u16>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum Subsampling {
+    Yuv444,
+    Yuv420,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct WebpOptions {
+    #[serde(default)]
+    pub lossless: bool,
+    pub near_lossless: Option<u8>,
+    pub alpha_quality: Option<u8>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -69,6 +310,8 @@ pub struct Watermark {
     pub alpha: f64,
     #[serde(default)]
     pub size: Size,
+    #[serde(default)]
+    pub filter: Option<Filter>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -84,7 +327,7 @@ pub enum WatermarkPosition {
     RightBottom,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Copy)]
 pub enum Rotation {
     R90,
     R180,
@@ -97,28 +340,75 @@ pub struct Point {
     pub y: i32,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum ImageFormat {
     Png,
     Jpeg,
     Webp,
+    Avif,
+    Gif,
+    Auto,
 }
 
 fn default_quality() -> i32 {
     100
 }
 
+fn default_auto_orient() -> bool {
+    true
+}
+
+fn default_avif_speed() -> u8 {
+    6
+}
+
+fn default_max_output_pixels() -> u64 {
+    50_000_000
+}
+
+fn default_http_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_http_max_body_size() -> usize {
+    20 * 1024 * 1024
+}
+
 impl fmt::Display for ImageFormat {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let as_str = match self {
             ImageFormat::Jpeg => "jpeg",
             ImageFormat::Png => "png",
             ImageFormat::Webp => "webp",
+            ImageFormat::Avif => "avif",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Auto => "auto",
         };
         write!(f, "{}", as_str)
     }
 }
 
+impl ImageFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "image/jpeg" => Some(ImageFormat::Jpeg),
+            "image/png" => Some(ImageFormat::Png),
+            "image/webp" => Some(ImageFormat::Webp),
+            "image/avif" => Some(ImageFormat::Avif),
+            "image/gif" => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+
+    /// Formats OpenCV cannot encode are processed as PNG.
+    pub fn working_format(self) -> ImageFormat {
+        match self {
+            ImageFormat::Avif | ImageFormat::Gif => ImageFormat::Png,
+            format => format,
+        }
+    }
+}
+
 impl Default for Size {
     fn default() -> Self {
         Size {
@@ -140,12 +430,359 @@ impl Default for ImageFormat {
     }
 }
 
+impl Default for Fit {
+    fn default() -> Self {
+        Fit::Contain
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata::StripAll
+    }
+}
+
+impl Default for Icc {
+    fn default() -> Self {
+        Icc::Convert
+    }
+}
+
+impl Default for Gravity {
+    fn default() -> Self {
+        Gravity::Center
+    }
+}
+
+impl Default for Focus {
+    fn default() -> Self {
+        Focus { x: 0.5, y: 0.5 }
+    }
+}
+
+impl Default for CropUnit {
+    fn default() -> Self {
+        CropUnit::Pixels
+    }
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Color {
+            red: 255,
+            green: 255,
+            blue: 255,
+            alpha: 255,
+        }
+    }
+}
+
 impl Default for WatermarkPosition {
     fn default() -> Self {
         WatermarkPosition::LeftTop
     }
 }
 
+impl ProcessImageRequest {
+    /// Renders the request in a stable form, so equivalent query strings share a cache entry.
+    pub fn canonical(&self) -> String {
+        let watermarks = self
+            .watermarks
+            .iter()
+            .map(Watermark::canonical)
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut canonical = format!(
+            "size={};format={};quality={};rotation={:?};watermarks=[{}]",
+            self.size.canonical(),
+            self.format,
+            self.quality,
+            self.rotation,
+            watermarks
+        );
+        if let Some(speed) = self.speed {
+            canonical.push_str(&format!(";speed={}", speed));
+        }
+        if self.page != 0 {
+            canonical.push_str(&format!(";page={}", self.page));
+        }
+        if self.webp != WebpOptions::default() {
+            canonical.push_str(&format!(";webp={}", self.webp.canonical()));
+        }
+        if self.jpeg != JpegOptions::default() {
+            canonical.push_str(&format!(";jpeg={}", self.jpeg.canonical()));
+        }
+        if self.fit != Fit::Contain {
+            canonical.push_str(&format!(";fit={:?}", self.fit));
+        }
+        if self.background != Color::default() {
+            canonical.push_str(&format!(";background={}", self.background));
+        }
+        if let Some(crop) = &self.crop {
+            canonical.push_str(&format!(";crop={}", crop.canonical()));
+        }
+        if let Some(focus) = self.focus {
+            canonical.push_str(&format!(";focus={}x{}", focus.x, focus.y));
+        } else if self.gravity != Gravity::Center {
+            canonical.push_str(&format!(";gravity={:?}", self.gravity));
+        }
+        if self.upscale {
+            canonical.push_str(";upscale");
+        }
+        if let Some(dpr) = self.dpr.filter(|dpr| (dpr - 1.0).abs() > std::f64::EPSILON) {
+            canonical.push_str(&format!(";dpr={}", dpr));
+        }
+        if let Some(filter) = self.filter {
+            canonical.push_str(&format!(";filter={:?}", filter));
+        }
+        if !self.auto_orient {
+            canonical.push_str(";auto_orient=false");
+        }
+        if let Some(angle) = self.angle {
+            canonical.push_str(&format!(";angle={};expand={}", angle, self.expand));
+        }
+        if self.flip {
+            canonical.push_str(";flip");
+        }
+        if self.flop {
+            canonical.push_str(";flop");
+        }
+        if let Some(metadata) = self.metadata {
+            canonical.push_str(&format!(";metadata={:?}", metadata));
+        }
+        if let Some(icc) = self.icc {
+            canonical.push_str(&format!(";icc={:?}", icc));
+        }
+        canonical
+    }
+
+    pub fn validate(&self, jpeg_defaults: &JpegOptions) -> Result<(), String> {
+        self.jpeg.or(jpeg_defaults).validate()?;
+        if let Some(speed) = self.speed.filter(|speed| *speed > MAX_AVIF_SPEED) {
+            return Err(format!(
+                "Speed {} is not valid, it goes from 0 to {}",
+                speed, MAX_AVIF_SPEED
+            ));
+        }
+        let percentages = [
+            ("webp[near_lossless]", self.webp.near_lossless),
+            ("webp[alpha_quality]", self.webp.alpha_quality),
+        ];
+        for (name, value) in percentages.iter() {
+            if let Some(value) = value.filter(|value| *value > 100) {
+                return Err(format!(
+                    "{} {} is not valid, it goes from 0 to 100",
+                    name, value
+                ));
+            }
+        }
+        if let Some(crop) = &self.crop {
+            crop.validate()?;
+        }
+        if let Some(focus) = self.focus {
+            let in_bounds = |measure: f64| measure >= 0.0 && measure <= 1.0;
+            if !in_bounds(focus.x) || !in_bounds(focus.y) {
+                return Err(format!(
+                    "Focus {:?} is not valid, its coordinates go from 0 to 1",
+                    focus
+                ));
+            }
+        }
+        if let Some(angle) = self.angle.filter(|angle| !angle.is_finite()) {
+            return Err(format!("Angle {} is not valid", angle));
+        }
+        if let Some(dpr) = self.dpr.filter(|dpr| !(*dpr >= 1.0 && *dpr <= MAX_DPR)) {
+            return Err(format!(
+                "Device pixel ratio {} is not valid, it goes from 1 to {}",
+                dpr, MAX_DPR
+            ));
+        }
+        Ok(())
+    }
+
+    /// `Smart` crops start from the centre, and are moved once the image is decoded.
+    pub fn focus(&self) -> Focus {
+        self.focus.unwrap_or_else(|| self.gravity.focus())
+    }
+}
+
+impl Gravity {
+    fn focus(self) -> Focus {
+        let (x, y) = match self {
+            Gravity::Center | Gravity::Smart => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::NorthEast => (1.0, 0.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::SouthEast => (1.0, 1.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::SouthWest => (0.0, 1.0),
+            Gravity::West => (0.0, 0.5),
+            Gravity::NorthWest => (0.0, 0.0),
+        };
+        Focus { x, y }
+    }
+}
+
+impl Crop {
+    /// Pixel coordinates are checked against the image once it is decoded.
+    fn validate(&self) -> Result<(), String> {
+        let in_bounds = match self.unit {
+            CropUnit::Pixels => true,
+            CropUnit::Relative => {
+                self.x + self.width <= 1.0 + RELATIVE_EPSILON
+                    && self.y + self.height <= 1.0 + RELATIVE_EPSILON
+            }
+        };
+        if self.x < 0.0 || self.y < 0.0 || self.width <= 0.0 || self.height <= 0.0 || !in_bounds {
+            return Err(format!("Crop {:?} is not valid", self));
+        }
+        Ok(())
+    }
+
+    fn canonical(&self) -> String {
+        format!(
+            "{:?}:{}x{}:{}x{}",
+            self.unit, self.x, self.y, self.width, self.height
+        )
+    }
+}
+
+impl JpegOptions {
+    pub fn or(&self, defaults: &JpegOptions) -> JpegOptions {
+        JpegOptions {
+            progressive: self.progressive.or(defaults.progressive),
+            optimize: self.optimize.or(defaults.optimize),
+            subsampling: self.subsampling.or(defaults.subsampling),
+            restart_interval: self.restart_interval.or(defaults.restart_interval),
+        }
+    }
+
+    /// 4:4:4 subsampling is only available through ImageMagick, which cannot write restart
+    /// markers.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.subsampling, self.restart_interval) {
+            (Some(Subsampling::Yuv444), Some(interval)) if interval > 0 => {
+                Err("JPEG 4:4:4 subsampling cannot be combined with a restart interval".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn canonical(&self) -> String {
+        format!(
+            "{:?}:{:?}:{:?}:{:?}",
+            self.progressive, self.optimize, self.subsampling, self.restart_interval
+        )
+    }
+}
+
+impl WebpOptions {
+    fn canonical(&self) -> String {
+        let measure = |m: Option<u8>| m.map_or("auto".to_string(), |m| m.to_string());
+        format!(
+            "{}:{}:{}",
+            self.lossless,
+            measure(self.near_lossless),
+            measure(self.alpha_quality)
+        )
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(hex: String) -> Result<Self, Self::Error> {
+        let digits = hex.trim_start_matches('#');
+        let component = |index: usize| {
+            digits
+                .get(index * 2..index * 2 + 2)
+                .and_then(|component| u8::from_str_radix(component, 16).ok())
+        };
+        let components = match digits.len() {
+            6 => (component(0), component(1), component(2), Some(255)),
+            8 => (component(0), component(1), component(2), component(3)),
+            _ => (None, None, None, None),
+        };
+        match components {
+            (Some(red), Some(green), Some(blue), Some(alpha)) => Ok(Color {
+                red,
+                green,
+                blue,
+                alpha,
+            }),
+            _ => Err(format!(
+                "Color {:?} is not valid, it is written as RRGGBB or RRGGBBAA",
+                hex
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{:02x}{:02x}{:02x}{:02x}",
+            self.red, self.green, self.blue, self.alpha
+        )
+    }
+}
+
+impl Watermark {
+    fn canonical(&self) -> String {
+        let mut canonical = format!(
+            "{}:{:?}:{}x{}:{}:{}",
+            self.filename,
+            self.origin,
+            self.position.x,
+            self.position.y,
+            self.alpha,
+            self.size.canonical()
+        );
+        if let Some(filter) = self.filter {
+            canonical.push_str(&format!(":{:?}", filter));
+        }
+        canonical
+    }
+}
+
+impl Size {
+    fn canonical(&self) -> String {
+        let measure = |m: Option<i32>| m.map_or("auto".to_string(), |m| m.to_string());
+        format!("{}x{}", measure(self.width), measure(self.height))
+    }
+}
+
+impl CacheControlRules {
+    fn for_status(&self, status: StatusCode) -> Option<&str> {
+        let value = if status.is_success() || status == StatusCode::NOT_MODIFIED {
+            &self.success
+        } else if status == StatusCode::NOT_FOUND {
+            &self.not_found
+        } else {
+            &self.error
+        };
+        value.as_ref().map(String::as_str)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &String> {
+        self.success
+            .iter()
+            .chain(self.not_found.iter())
+            .chain(self.error.iter())
+    }
+}
+
+impl CacheControlConfig {
+    /// The rules of the source take precedence over the global ones.
+    pub fn for_status(&self, source: Option<&str>, status: StatusCode) -> Option<&str> {
+        source
+            .and_then(|name| self.sources.get(name))
+            .and_then(|rules| rules.for_status(status))
+            .or_else(|| self.default.for_status(status))
+    }
+}
+
 impl Configuration {
     pub fn new() -> Result<Self, ConfigError> {
         let mut s = Config::new();
@@ -160,10 +797,71 @@ impl Configuration {
         s.merge(File::with_name(&format!("config/{}", env)).required(false))?;
 
         // Deserialize (and thus freeze) the entire configuration as
-        s.try_into()
+        let mut config: Configuration = s.try_into()?;
+        config.validate()?;
+        let profile = match &config.srgb_profile {
+            Some(path) => std::fs::read(path).map_err(|e| {
+                ConfigError::Message(format!("Failed to read the sRGB profile {}: {}", path, e))
+            })?,
+            None => BUILTIN_SRGB_PROFILE.to_vec(),
+        };
+        config.srgb = Some(Arc::new(profile));
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let source_names = self
+            .default_source
+            .iter()
+            .chain(self.watermark_source.iter());
+        for name in source_names {
+            if !self.sources.contains_key(name) {
+                return Err(ConfigError::Message(format!(
+                    "Source {} is not configured",
+                    name
+                )));
+            }
+        }
+        if self.avif_speed > MAX_AVIF_SPEED {
+            return Err(ConfigError::Message(format!(
+                "AVIF speed {} is not valid, it goes from 0 to {}",
+                self.avif_speed, MAX_AVIF_SPEED
+            )));
+        }
+        self.jpeg.validate().map_err(ConfigError::Message)?;
+        if self.max_output_pixels == 0 {
+            return Err(ConfigError::Message(
+                "The maximum number of output pixels must be positive".to_string(),
+            ));
+        }
+        for name in self.cache_control.sources.keys() {
+            if !self.sources.contains_key(name) {
+                return Err(ConfigError::Message(format!(
+                    "Cache-Control rules are set for source {}, which is not configured",
+                    name
+                )));
+            }
+        }
+        let cache_control_rules =
+            std::iter::once(&self.cache_control.default).chain(self.cache_control.sources.values());
+        for value in cache_control_rules.flat_map(CacheControlRules::values) {
+            if HeaderValue::from_str(value).is_err() {
+                return Err(ConfigError::Message(format!(
+                    "Invalid Cache-Control value: {}",
+                    value
+                )));
+            }
+        }
+        Ok(())
     }
 }
 
+pub fn sha1_hex(data: &str) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(data.as_bytes());
+    hasher.digest().to_string()
+}
+
 fn get_ratio(desired_measure: i32, original_measure: i32, opposite_orig_measure: i32) -> i32 {
     let ratio = desired_measure as f32 / original_measure as f32;
     (opposite_orig_measure as f32 * ratio) as i32
@@ -225,6 +923,33 @@ pub fn get_target_size(
     }
 }
 
+/// Like `get_target_size`, but also enlarges vector images smaller than the desired size.
+pub fn get_scaled_size(
+    original_width: i32,
+    original_height: i32,
+    desired_size: &Size,
+    fit: Fit,
+) -> Result<(i32, i32), InvalidSizeError> {
+    if is_negative_or_zero(desired_size) {
+        return Err(InvalidSizeError::new(desired_size));
+    }
+    if let (Fit::Fill, Some(w), Some(h)) = (fit, desired_size.width, desired_size.height) {
+        return Ok((w, h));
+    }
+    let width_ratio = desired_size.width.map(|w| w as f32 / original_width as f32);
+    let height_ratio = desired_size
+        .height
+        .map(|h| h as f32 / original_height as f32);
+    let ratio = match (width_ratio, height_ratio) {
+        (Some(w), Some(h)) if fit == Fit::Cover => w.max(h),
+        (Some(w), Some(h)) => w.min(h),
+        (Some(r), None) | (None, Some(r)) => r,
+        (None, None) => return Ok((original_width, original_height)),
+    };
+    let scale = |measure: i32| ((measure as f32 * ratio).round() as i32).max(1);
+    Ok((scale(original_width), scale(original_height)))
+}
+
 pub fn get_watermark_borders(
     width: i32,
     height: i32,
@@ -678,6 +1403,104 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_scaled_size() {
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: Some(300),
+                    height: None
+                },
+                Fit::Contain
+            ),
+            Ok((300, 150))
+        );
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: Some(400),
+                    height: Some(100)
+                },
+                Fit::Contain
+            ),
+            Ok((200, 100))
+        );
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: None,
+                    height: Some(10)
+                },
+                Fit::Contain
+            ),
+            Ok((20, 10))
+        );
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: None,
+                    height: None
+                },
+                Fit::Contain
+            ),
+            Ok((100, 50))
+        );
+        assert!(get_scaled_size(
+            100,
+            50,
+            &Size {
+                width: Some(0),
+                height: None
+            },
+            Fit::Contain
+        )
+        .is_err());
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: Some(400),
+                    height: Some(100)
+                },
+                Fit::Cover
+            ),
+            Ok((400, 200))
+        );
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: Some(400),
+                    height: Some(100)
+                },
+                Fit::Fill
+            ),
+            Ok((400, 100))
+        );
+        assert_eq!(
+            get_scaled_size(
+                100,
+                50,
+                &Size {
+                    width: Some(400),
+                    height: None
+                },
+                Fit::Fill
+            ),
+            Ok((400, 200))
+        );
+    }
+
     #[test]
     fn test_center_watermark() {
         assert_eq!(
@@ -799,4 +1622,329 @@ mod tests {
             (0, 0, 90, 90)
         );
     }
+
+    fn parse_request(query: &str) -> ProcessImageRequest {
+        serde_qs::Config::new(5, false)
+            .deserialize_str(query)
+            .expect("Invalid query string")
+    }
+
+    #[test]
+    fn test_canonical_request() {
+        assert_eq!(
+            parse_request("").canonical(),
+            parse_request("format=Jpeg&quality=100").canonical()
+        );
+        assert_eq!(
+            parse_request("size[width]=100&rotation=R90&size[height]=50").canonical(),
+            parse_request("rotation=R90&size[height]=50&size[width]=100").canonical()
+        );
+        assert_eq!(
+            parse_request("watermarks[0][filename]=watermark&watermarks[0][alpha]=0.5")
+                .canonical(),
+            parse_request(
+                "watermarks[0][alpha]=0.50&watermarks[0][origin]=LeftTop&watermarks[0][filename]=watermark"
+            )
+            .canonical()
+        );
+        assert_ne!(
+            parse_request("size[width]=100").canonical(),
+            parse_request("size[height]=100").canonical()
+        );
+        assert_ne!(
+            parse_request("format=Png").canonical(),
+            parse_request("format=Webp").canonical()
+        );
+        assert_ne!(
+            parse_request("format=Avif").canonical(),
+            parse_request("format=Avif&speed=2").canonical()
+        );
+        assert_eq!(
+            parse_request("").canonical(),
+            parse_request("page=0").canonical()
+        );
+        assert_ne!(
+            parse_request("page=0").canonical(),
+            parse_request("page=1").canonical()
+        );
+        assert_eq!(
+            parse_request("").canonical(),
+            parse_request("webp[lossless]=false").canonical()
+        );
+        assert_ne!(
+            parse_request("webp[near_lossless]=60").canonical(),
+            parse_request("webp[alpha_quality]=60").canonical()
+        );
+        assert_ne!(
+            parse_request("jpeg[progressive]=true").canonical(),
+            parse_request("jpeg[optimize]=true").canonical()
+        );
+        assert_eq!(
+            parse_request("fit=Contain&background=FFFFFF").canonical(),
+            parse_request("background=ffffffff").canonical()
+        );
+        assert_ne!(
+            parse_request("fit=Cover").canonical(),
+            parse_request("fit=Fill").canonical()
+        );
+        assert_eq!(
+            parse_request("gravity=Center").canonical(),
+            parse_request("").canonical()
+        );
+        assert_eq!(
+            parse_request("gravity=North&focus[x]=0.5&focus[y]=0").canonical(),
+            parse_request("focus[x]=0.5&focus[y]=0").canonical()
+        );
+        assert_ne!(
+            parse_request("icc=Preserve").canonical(),
+            parse_request("").canonical()
+        );
+        assert_ne!(
+            parse_request("metadata=KeepIcc").canonical(),
+            parse_request("metadata=KeepCopyright").canonical()
+        );
+        assert_ne!(
+            parse_request("flip=true").canonical(),
+            parse_request("flop=true").canonical()
+        );
+        assert_ne!(
+            parse_request("angle=3").canonical(),
+            parse_request("angle=3&expand=true").canonical()
+        );
+        assert_eq!(
+            parse_request("expand=true").canonical(),
+            parse_request("").canonical()
+        );
+        assert_eq!(
+            parse_request("auto_orient=true").canonical(),
+            parse_request("").canonical()
+        );
+        assert_ne!(
+            parse_request("auto_orient=false").canonical(),
+            parse_request("").canonical()
+        );
+        assert_ne!(
+            parse_request("filter=Area").canonical(),
+            parse_request("").canonical()
+        );
+        assert_ne!(
+            parse_request("watermarks[0][filename]=watermark&watermarks[0][filter]=Lanczos")
+                .canonical(),
+            parse_request("watermarks[0][filename]=watermark").canonical()
+        );
+        assert_eq!(
+            parse_request("upscale=false&dpr=1").canonical(),
+            parse_request("").canonical()
+        );
+        assert_ne!(
+            parse_request("size[width]=100&dpr=2").canonical(),
+            parse_request("size[width]=100&upscale=true&dpr=2").canonical()
+        );
+        assert_ne!(
+            parse_request("crop[width]=1&crop[height]=1").canonical(),
+            parse_request("crop[width]=1&crop[height]=1&crop[unit]=Relative").canonical()
+        );
+    }
+
+    #[test]
+    fn test_color() {
+        assert_eq!(
+            Color::try_from("ff8000".to_string()),
+            Ok(Color {
+                red: 255,
+                green: 128,
+                blue: 0,
+                alpha: 255
+            })
+        );
+        assert_eq!(
+            Color::try_from("#00000080".to_string()),
+            Ok(Color {
+                red: 0,
+                green: 0,
+                blue: 0,
+                alpha: 128
+            })
+        );
+        assert!(Color::try_from("fff".to_string()).is_err());
+        assert!(Color::try_from("gg0000".to_string()).is_err());
+        assert!(Color::try_from("ff00€".to_string()).is_err());
+        assert_eq!(Color::default().to_string(), "#ffffffff");
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let defaults = JpegOptions::default();
+        assert!(parse_request("speed=9&webp[near_lossless]=100")
+            .validate(&defaults)
+            .is_ok());
+        assert!(parse_request("speed=10").validate(&defaults).is_err());
+        assert!(parse_request("webp[near_lossless]=101")
+            .validate(&defaults)
+            .is_err());
+        assert!(parse_request("webp[alpha_quality]=255")
+            .validate(&defaults)
+            .is_err());
+    }
+
+    #[test]
+    fn test_focus() {
+        assert_eq!(parse_request("").focus(), Focus::default());
+        assert_eq!(
+            parse_request("gravity=SouthWest").focus(),
+            Focus { x: 0.0, y: 1.0 }
+        );
+        assert_eq!(
+            parse_request("gravity=North&focus[x]=0.2&focus[y]=0.3").focus(),
+            Focus { x: 0.2, y: 0.3 }
+        );
+        let defaults = JpegOptions::default();
+        assert!(parse_request("focus[x]=1&focus[y]=0")
+            .validate(&defaults)
+            .is_ok());
+        assert!(parse_request("focus[x]=1.5&focus[y]=0")
+            .validate(&defaults)
+            .is_err());
+        assert!(parse_request("focus[x]=NaN&focus[y]=0")
+            .validate(&defaults)
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_dpr() {
+        let defaults = JpegOptions::default();
+        assert!(parse_request("dpr=1").validate(&defaults).is_ok());
+        assert!(parse_request("dpr=2.5").validate(&defaults).is_ok());
+        assert!(parse_request("dpr=4").validate(&defaults).is_ok());
+        assert!(parse_request("dpr=0.5").validate(&defaults).is_err());
+        assert!(parse_request("dpr=5").validate(&defaults).is_err());
+        assert!(parse_request("dpr=NaN").validate(&defaults).is_err());
+    }
+
+    #[test]
+    fn test_validate_angle() {
+        let defaults = JpegOptions::default();
+        assert!(parse_request("angle=-2.5").validate(&defaults).is_ok());
+        assert!(parse_request("angle=inf").validate(&defaults).is_err());
+        assert!(parse_request("angle=NaN").validate(&defaults).is_err());
+    }
+
+    #[test]
+    fn test_validate_crop() {
+        let defaults = JpegOptions::default();
+        assert!(parse_request("crop[width]=100&crop[height]=50")
+            .validate(&defaults)
+            .is_ok());
+        assert!(parse_request(
+            "crop[x]=0.7&crop[y]=0.1&crop[width]=0.3&crop[height]=0.9&crop[unit]=Relative"
+        )
+        .validate(&defaults)
+        .is_ok());
+        assert!(parse_request("crop[x]=-1&crop[width]=100&crop[height]=50")
+            .validate(&defaults)
+            .is_err());
+        assert!(parse_request("crop[width]=0&crop[height]=50")
+            .validate(&defaults)
+            .is_err());
+        assert!(
+            parse_request("crop[x]=0.5&crop[width]=0.6&crop[height]=1&crop[unit]=Relative")
+                .validate(&defaults)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_jpeg_options() {
+        let defaults = JpegOptions {
+            restart_interval: Some(8),
+            ..JpegOptions::default()
+        };
+        assert!(parse_request("jpeg[subsampling]=Yuv444")
+            .validate(&JpegOptions::default())
+            .is_ok());
+        assert!(parse_request("jpeg[subsampling]=Yuv420")
+            .validate(&defaults)
+            .is_ok());
+        assert!(parse_request("jpeg[subsampling]=Yuv444")
+            .validate(&defaults)
+            .is_err());
+        assert!(
+            parse_request("jpeg[subsampling]=Yuv444&jpeg[restart_interval]=0")
+                .validate(&defaults)
+                .is_ok()
+        );
+        assert!(
+            parse_request("jpeg[subsampling]=Yuv444&jpeg[restart_interval]=16")
+                .validate(&JpegOptions::default())
+                .is_err()
+        );
+    }
+
+    fn rules(success: &str, not_found: &str, error: &str) -> CacheControlRules {
+        CacheControlRules {
+            success: Some(success.to_string()),
+            not_found: Some(not_found.to_string()),
+            error: Some(error.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_cache_control_per_outcome() {
+        let config = CacheControlConfig {
+            default: rules("max-age=3600", "max-age=60", "no-store"),
+            sources: HashMap::new(),
+        };
+        assert_eq!(
+            config.for_status(None, StatusCode::OK),
+            Some("max-age=3600")
+        );
+        assert_eq!(
+            config.for_status(None, StatusCode::NOT_MODIFIED),
+            Some("max-age=3600")
+        );
+        assert_eq!(
+            config.for_status(None, StatusCode::NOT_FOUND),
+            Some("max-age=60")
+        );
+        assert_eq!(
+            config.for_status(None, StatusCode::BAD_REQUEST),
+            Some("no-store")
+        );
+        assert_eq!(
+            config.for_status(None, StatusCode::BAD_GATEWAY),
+            Some("no-store")
+        );
+    }
+
+    #[test]
+    fn test_cache_control_per_source() {
+        let mut sources = HashMap::new();
+        sources.insert(
+            "homes".to_string(),
+            CacheControlRules {
+                success: Some("max-age=60".to_string()),
+                ..CacheControlRules::default()
+            },
+        );
+        let config = CacheControlConfig {
+            default: CacheControlRules {
+                not_found: Some("max-age=10".to_string()),
+                ..CacheControlRules::default()
+            },
+            sources,
+        };
+        assert_eq!(
+            config.for_status(Some("homes"), StatusCode::OK),
+            Some("max-age=60")
+        );
+        assert_eq!(
+            config.for_status(Some("homes"), StatusCode::NOT_FOUND),
+            Some("max-age=10")
+        );
+        assert_eq!(config.for_status(Some("cars"), StatusCode::OK), None);
+        assert_eq!(
+            config.for_status(None, StatusCode::INTERNAL_SERVER_ERROR),
+            None
+        );
+    }
 }
@@ -0,0 +1,157 @@
+use crate::commons::ImageFormat;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+/// Output formats the client declared support for in its `Accept` header, beyond the
+/// universally supported JPEG and PNG.
+#[derive(Debug, Default, PartialEq)]
+pub struct AcceptedFormats {
+    pub webp: bool,
+    pub avif: bool,
+}
+
+impl AcceptedFormats {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .map(AcceptedFormats::from_accept)
+            .unwrap_or_default()
+    }
+
+    /// Parses an `Accept` header. Only explicit media types count, as browsers send
+    /// `image/*` and `*/*` regardless of the formats they can decode.
+    pub fn from_accept(accept: &str) -> Self {
+        let accepted = accept
+            .split(',')
+            .filter_map(|media_range| {
+                let mut params = media_range.split(';');
+                let media_type = params.next()?.trim().to_ascii_lowercase();
+                let quality = params
+                    .filter_map(|param| {
+                        let mut pair = param.splitn(2, '=');
+                        match (pair.next()?.trim(), pair.next()) {
+                            ("q", Some(q)) => q.trim().parse::<f32>().ok(),
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .unwrap_or(1.0);
+                if quality > 0.0 {
+                    Some(media_type)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        AcceptedFormats {
+            webp: accepted.iter().any(|media_type| media_type == "image/webp"),
+            avif: accepted.iter().any(|media_type| media_type == "image/avif"),
+        }
+    }
+
+    /// Stable form used in cache keys and ETags of negotiated images.
+    pub fn canonical(&self) -> String {
+        let formats = [("avif", self.avif), ("webp", self.webp)]
+            .iter()
+            .filter(|(_, accepted)| *accepted)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        if formats.is_empty() {
+            "none".to_string()
+        } else {
+            formats.join(",")
+        }
+    }
+}
+
+impl ImageFormat {
+    /// Resolves `Auto` to the best format the client accepts, keeping any other format as is.
+    pub fn negotiate(self, accepted: &AcceptedFormats, has_alpha: bool) -> ImageFormat {
+        match self {
+            ImageFormat::Auto if accepted.avif => ImageFormat::Avif,
+            ImageFormat::Auto if accepted.webp => ImageFormat::Webp,
+            ImageFormat::Auto if has_alpha => ImageFormat::Png,
+            ImageFormat::Auto => ImageFormat::Jpeg,
+            format => format,
+        }
+    }
+
+    /// Resolves the format of an animated image, or `None` when the requested format cannot
+    /// be animated and only the first frame is kept.
+    pub fn negotiate_animated(self, accepted: &AcceptedFormats) -> Option<ImageFormat> {
+        match self {
+            ImageFormat::Gif | ImageFormat::Webp => Some(self),
+            ImageFormat::Auto if accepted.webp => Some(ImageFormat::Webp),
+            ImageFormat::Auto => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepted_formats() {
+        assert!(AcceptedFormats::from_accept("image/webp,image/apng,image/*,*/*;q=0.8").webp);
+        assert!(AcceptedFormats::from_accept("image/png, Image/WebP;q=0.5").webp);
+        assert!(!AcceptedFormats::from_accept("image/webp;q=0, image/*").webp);
+        assert!(!AcceptedFormats::from_accept("image/*,*/*;q=0.8").webp);
+        assert!(!AcceptedFormats::from_accept("").webp);
+        assert!(AcceptedFormats::from_accept("image/avif,image/webp,*/*").avif);
+        assert!(!AcceptedFormats::from_accept("image/webp,*/*").avif);
+    }
+
+    #[test]
+    fn test_accepted_formats_canonical() {
+        assert_eq!(AcceptedFormats::from_accept("*/*").canonical(), "none");
+        assert_eq!(
+            AcceptedFormats::from_accept("image/webp,image/avif").canonical(),
+            "avif,webp"
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let webp = AcceptedFormats {
+            webp: true,
+            avif: false,
+        };
+        let avif = AcceptedFormats {
+            webp: true,
+            avif: true,
+        };
+        let none = AcceptedFormats::default();
+        assert_eq!(ImageFormat::Auto.negotiate(&avif, true), ImageFormat::Avif);
+        assert_eq!(ImageFormat::Auto.negotiate(&webp, false), ImageFormat::Webp);
+        assert_eq!(ImageFormat::Auto.negotiate(&webp, true), ImageFormat::Webp);
+        assert_eq!(ImageFormat::Auto.negotiate(&none, true), ImageFormat::Png);
+        assert_eq!(ImageFormat::Auto.negotiate(&none, false), ImageFormat::Jpeg);
+        assert_eq!(ImageFormat::Png.negotiate(&webp, false), ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_negotiate_animated() {
+        let avif = AcceptedFormats {
+            webp: true,
+            avif: true,
+        };
+        let none = AcceptedFormats::default();
+        assert_eq!(
+            ImageFormat::Auto.negotiate_animated(&avif),
+            Some(ImageFormat::Webp)
+        );
+        assert_eq!(
+            ImageFormat::Auto.negotiate_animated(&none),
+            Some(ImageFormat::Gif)
+        );
+        assert_eq!(
+            ImageFormat::Gif.negotiate_animated(&avif),
+            Some(ImageFormat::Gif)
+        );
+        assert_eq!(ImageFormat::Jpeg.negotiate_animated(&avif), None);
+        assert_eq!(ImageFormat::Avif.negotiate_animated(&avif), None);
+    }
+}
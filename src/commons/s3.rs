@@ -1,15 +1,65 @@
-use actix_web::web::Bytes;
+use crate::commons::conditional::Validators;
+use crate::commons::source::{ImageFuture, ImageSource, SourceImage, ValidatorsFuture};
 use actix_web::Error;
 use futures::future::Future;
 use futures::Stream;
-use rusoto_core::RusotoError;
-use rusoto_s3::{GetObjectError, GetObjectRequest, S3Client, S3};
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_s3::{
+    GetObjectError, GetObjectRequest, HeadObjectError, HeadObjectRequest, S3Client, S3,
+};
+
+pub struct S3Source {
+    client: S3Client,
+    bucket: String,
+}
+
+impl S3Source {
+    pub fn new(
+        region: Region,
+        bucket: &str,
+        access_key_id: Option<&str>,
+        secret_access_key: Option<&str>,
+    ) -> Self {
+        S3Source {
+            client: new_client(region, access_key_id, secret_access_key),
+            bucket: bucket.to_string(),
+        }
+    }
+}
+
+impl ImageSource for S3Source {
+    fn get_image(&self, key: &str) -> ImageFuture {
+        Box::new(get_image(&self.client, &self.bucket, key))
+    }
+
+    fn get_validators(&self, key: &str) -> ValidatorsFuture {
+        Box::new(get_validators(&self.client, &self.bucket, key))
+    }
+}
+
+/// Creates a client with static credentials when they are given, or with the default AWS
+/// credentials chain otherwise.
+pub fn new_client(
+    region: Region,
+    access_key_id: Option<&str>,
+    secret_access_key: Option<&str>,
+) -> S3Client {
+    match (access_key_id, secret_access_key) {
+        (Some(key), Some(secret)) => S3Client::new_with(
+            HttpClient::new().expect("Failed to create the S3 HTTP client"),
+            StaticProvider::new_minimal(key.to_string(), secret.to_string()),
+            region,
+        ),
+        _ => S3Client::new(region),
+    }
+}
 
 pub fn get_image(
     client: &S3Client,
     bucket: &str,
     filename: &str,
-) -> impl Future<Item = Bytes, Error = Error> {
+) -> impl Future<Item = SourceImage, Error = Error> {
     info!("Fetching image {} from S3 bucket: {}", filename, bucket);
     client
         .get_object(GetObjectRequest {
@@ -28,11 +78,53 @@ pub fn get_image(
         })
         .map(|res| {
             info!("Response {:?}", res);
+            let validators = Validators {
+                etag: res.e_tag,
+                last_modified: res.last_modified,
+            };
             let stream = res.body.expect("Error retrieving the body stream");
-            stream.concat2().map_err(|e| {
-                error!("Error fetching file from S3: {:?}", e);
-                actix_web::error::ErrorInternalServerError(e)
-            })
+            stream
+                .concat2()
+                .map(move |body| SourceImage { body, validators })
+                .map_err(|e| {
+                    error!("Error fetching file from S3: {:?}", e);
+                    actix_web::error::ErrorInternalServerError(e)
+                })
         })
         .flatten()
 }
+
+pub fn get_validators(
+    client: &S3Client,
+    bucket: &str,
+    filename: &str,
+) -> impl Future<Item = Validators, Error = Error> {
+    debug!(
+        "Fetching validators of {} from S3 bucket: {}",
+        filename, bucket
+    );
+    let key = filename.to_string();
+    client
+        .head_object(HeadObjectRequest {
+            bucket: bucket.to_string(),
+            key: filename.to_string(),
+            ..Default::default()
+        })
+        .map_err(move |e| match e {
+            RusotoError::Service(HeadObjectError::NoSuchKey(key)) => {
+                actix_web::error::ErrorNotFound(format!("File {} not found", key))
+            }
+            // Responses to HEAD requests have no body to tell the error from.
+            RusotoError::Unknown(ref res) if res.status.as_u16() == 404 => {
+                actix_web::error::ErrorNotFound(format!("File {} not found", key))
+            }
+            e => {
+                error!("Error fetching file metadata from S3: {:?}", e);
+                actix_web::error::ErrorInternalServerError(e)
+            }
+        })
+        .map(|res| Validators {
+            etag: res.e_tag,
+            last_modified: res.last_modified,
+        })
+}
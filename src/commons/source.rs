@@ -0,0 +1,182 @@
+use crate::commons::conditional::Validators;
+use crate::commons::errors::InvalidKeyError;
+use crate::commons::filesystem::FilesystemSource;
+use crate::commons::http::HttpSource;
+use crate::commons::s3::S3Source;
+use crate::commons::SourceConfig;
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures::future::Future;
+use percent_encoding::percent_decode;
+use std::collections::HashMap;
+
+pub type ImageFuture = Box<dyn Future<Item = SourceImage, Error = Error>>;
+pub type ValidatorsFuture = Box<dyn Future<Item = Validators, Error = Error>>;
+
+/// An original or watermark image, as read from its source.
+pub struct SourceImage {
+    pub body: Bytes,
+    pub validators: Validators,
+}
+
+/// A storage backend from which original and watermark images are fetched.
+pub trait ImageSource {
+    fn get_image(&self, key: &str) -> ImageFuture;
+
+    /// Validators of an image, without reading the image itself.
+    fn get_validators(&self, key: &str) -> ValidatorsFuture;
+}
+
+/// The configured image sources, addressed by the name they have in the configuration.
+pub struct ImageSources {
+    sources: HashMap<String, Box<dyn ImageSource + Send + Sync>>,
+}
+
+impl ImageSources {
+    pub fn new(config: &HashMap<String, SourceConfig>) -> Self {
+        ImageSources {
+            sources: config
+                .iter()
+                .map(|(name, source)| (name.clone(), new_image_source(source)))
+                .collect(),
+        }
+    }
+
+    /// Splits a normalised request path into the source it addresses and the key within it.
+    /// Paths whose first segment is not a configured source are read from the default source.
+    pub fn resolve(&self, path: &str, default_source: Option<&str>) -> Option<(String, String)> {
+        let mut segments = path.splitn(2, '/');
+        if let (Some(prefix), Some(key)) = (segments.next(), segments.next()) {
+            if self.sources.contains_key(prefix) {
+                return Some((prefix.to_string(), key.to_string()));
+            }
+        }
+        default_source.map(|source| (source.to_string(), path.to_string()))
+    }
+
+    pub fn get_image(&self, source: &str, key: &str) -> ImageFuture {
+        match self.sources.get(source) {
+            Some(image_source) => image_source.get_image(key),
+            None => Box::new(futures::failed(source_not_found(source))),
+        }
+    }
+
+    pub fn get_validators(&self, source: &str, key: &str) -> ValidatorsFuture {
+        match self.sources.get(source) {
+            Some(image_source) => image_source.get_validators(key),
+            None => Box::new(futures::failed(source_not_found(source))),
+        }
+    }
+}
+
+fn source_not_found(source: &str) -> Error {
+    actix_web::error::ErrorNotFound(format!("Source {} not found", source))
+}
+
+pub fn new_image_source(config: &SourceConfig) -> Box<dyn ImageSource + Send + Sync> {
+    match config {
+        SourceConfig::S3 {
+            region,
+            bucket,
+            access_key_id,
+            secret_access_key,
+        } => Box::new(S3Source::new(
+            region.clone(),
+            bucket,
+            access_key_id.as_ref().map(String::as_str),
+            secret_access_key.as_ref().map(String::as_str),
+        )),
+        SourceConfig::Filesystem { root } => Box::new(FilesystemSource::new(root)),
+        SourceConfig::Http {
+            base_url,
+            allowed_hosts,
+            timeout_ms,
+            max_body_size,
+        } => Box::new(HttpSource::new(
+            base_url,
+            allowed_hosts,
+            *timeout_ms,
+            *max_body_size,
+        )),
+    }
+}
+
+/// Percent-decodes a raw request path and checks it is a valid object key.
+pub fn normalize_key(raw: &str) -> Result<String, InvalidKeyError> {
+    let decoded = percent_decode(raw.as_bytes())
+        .decode_utf8()
+        .map_err(|_| InvalidKeyError::new(raw))?;
+    validate_key(&decoded)?;
+    Ok(decoded.into_owned())
+}
+
+/// Rejects keys which are empty, have empty segments or could escape a directory root.
+pub fn validate_key(key: &str) -> Result<(), InvalidKeyError> {
+    let has_invalid_segment = key
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..");
+    if has_invalid_segment || key.contains('\\') || key.contains('\0') {
+        Err(InvalidKeyError::new(key))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_nested_key() {
+        assert_eq!(
+            normalize_key("listings/2024/abc.jpg"),
+            Ok("listings/2024/abc.jpg".to_string())
+        );
+        assert_eq!(
+            normalize_key("listings%2F2024/my%20image.jpg"),
+            Ok("listings/2024/my image.jpg".to_string())
+        );
+    }
+
+    #[test]
+    fn test_normalize_invalid_key() {
+        assert!(normalize_key("").is_err());
+        assert!(normalize_key("/abc.jpg").is_err());
+        assert!(normalize_key("listings//abc.jpg").is_err());
+        assert!(normalize_key("listings/abc.jpg/").is_err());
+        assert!(normalize_key("../abc.jpg").is_err());
+        assert!(normalize_key("listings/../../abc.jpg").is_err());
+        assert!(normalize_key("listings/./abc.jpg").is_err());
+        assert!(normalize_key("%2E%2E/abc.jpg").is_err());
+        assert!(normalize_key("listings%2F..%2Fabc.jpg").is_err());
+        assert!(normalize_key("..%5Cabc.jpg").is_err());
+        assert!(normalize_key("abc%00.jpg").is_err());
+        assert!(normalize_key("abc%FF.jpg").is_err());
+    }
+
+    #[test]
+    fn test_resolve_source() {
+        let mut config = HashMap::new();
+        config.insert(
+            "cars".to_string(),
+            SourceConfig::Filesystem {
+                root: "tests/resources".to_string(),
+            },
+        );
+        let sources = ImageSources::new(&config);
+        assert_eq!(
+            sources.resolve("cars/2024/abc.jpg", Some("homes")),
+            Some(("cars".to_string(), "2024/abc.jpg".to_string()))
+        );
+        assert_eq!(
+            sources.resolve("listings/2024/abc.jpg", Some("homes")),
+            Some(("homes".to_string(), "listings/2024/abc.jpg".to_string()))
+        );
+        assert_eq!(
+            sources.resolve("abc.jpg", Some("homes")),
+            Some(("homes".to_string(), "abc.jpg".to_string()))
+        );
+        assert_eq!(sources.resolve("cars", None), None);
+        assert_eq!(sources.resolve("listings/abc.jpg", None), None);
+    }
+}
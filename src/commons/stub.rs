@@ -0,0 +1,53 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Serves a single canned response and sends back the request line it received.
+pub fn serve(
+    status: &'static str,
+    headers: &'static str,
+    body: &'static [u8],
+) -> (String, mpsc::Receiver<String>) {
+    serve_delayed(status, headers, body, Duration::from_millis(0))
+}
+
+/// Like `serve`, but only sends the body `delay` after the head.
+pub fn serve_delayed(
+    status: &'static str,
+    headers: &'static str,
+    body: &'static [u8],
+    delay: Duration,
+) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind stub server");
+    let addr = listener.local_addr().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("Unable to accept connection");
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).expect("Unable to read request");
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8_lossy(&request).into_owned();
+        let head = format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            headers,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.flush().unwrap();
+        thread::sleep(delay);
+        // The client may have given up waiting
+        let _ = stream.write_all(body);
+        // The receiver may be gone when the test does not check the request
+        let _ = sender.send(request.lines().next().unwrap_or("").to_string());
+    });
+    (format!("http://{}", addr), receiver)
+}
@@ -0,0 +1,303 @@
+use crate::commons::errors::*;
+use crate::commons::geometry::{get_crop_region, get_layout, limit_pixels, Resize};
+use crate::commons::*;
+use crate::image_processor::metadata::Profiles;
+use crate::image_processor::{resize_filter, set_encoder_options, EncodeOptions};
+
+use magick_rust::bindings::{
+    MagickBooleanType_MagickTrue, MagickCoalesceImages, MagickCropImage, MagickExtentImage,
+    MagickFlipImage, MagickFlopImage, MagickGetImageHeight, MagickGetImageWidth,
+    MagickGetImagesBlob, MagickGetNumberImages, MagickNextImage, MagickRelinquishMemory,
+    MagickResetIterator, MagickResizeImage, MagickRotateImage, MagickSetImageBackgroundColor,
+    MagickSetImageCompressionQuality, MagickSetImageFormat, MagickSetImagePage,
+    MagickSetIteratorIndex,
+};
+use magick_rust::{MagickWand, PixelWand};
+use std::ffi::CString;
+use std::os::raw::c_void;
+
+/// An input decoded by ImageMagick because OpenCV cannot read it.
+pub enum Decoded {
+    /// Every frame of an animation, coalesced into full canvases.
+    Frames(MagickWand),
+    /// A single frame image, re-encoded as PNG.
+    Still(Vec<u8>),
+}
+
+pub fn is_gif(buffer: &[u8]) -> bool {
+    buffer.starts_with(b"GIF87a") || buffer.starts_with(b"GIF89a")
+}
+
+/// Animated WebP images use the extended `VP8X` chunk with the animation flag set.
+fn is_animated_webp(buffer: &[u8]) -> bool {
+    buffer.len() > 20
+        && &buffer[0..4] == b"RIFF"
+        && &buffer[8..12] == b"WEBP"
+        && &buffer[12..16] == b"VP8X"
+        && buffer[20] & 0x02 != 0
+}
+
+/// Decodes GIF and animated WebP inputs, returning `None` for any other input.
+pub fn decode(buffer: &[u8]) -> Result<Option<Decoded>, MagickError> {
+    if !is_gif(buffer) && !is_animated_webp(buffer) {
+        return Ok(None);
+    }
+    let wand = MagickWand::new();
+    wand.read_image_blob(buffer)?;
+    let frames = MagickWand {
+        wand: unsafe { MagickCoalesceImages(wand.wand) },
+    };
+    if frames.wand.is_null() {
+        return Err("Failed to coalesce the frames of the image".into());
+    }
+    if frame_count(&frames) > 1 {
+        Ok(Some(Decoded::Frames(frames)))
+    } else {
+        first_frame(&frames).map(|png| Some(Decoded::Still(png)))
+    }
+}
+
+pub fn frame_count(wand: &MagickWand) -> usize {
+    unsafe { MagickGetNumberImages(wand.wand) }
+}
+
+/// Encodes the first frame as PNG, for outputs which cannot be animated.
+pub fn first_frame(frames: &MagickWand) -> Result<Vec<u8>, MagickError> {
+    unsafe {
+        MagickSetIteratorIndex(frames.wand, 0);
+    }
+    frames.write_image_blob("png").map_err(|e| e.into())
+}
+
+/// Runs `f` with the wand positioned on each frame in turn.
+pub fn for_each_frame<F>(wand: &MagickWand, mut f: F) -> Result<(), MagickError>
+where
+    F: FnMut(&MagickWand) -> Result<(), MagickError>,
+{
+    unsafe {
+        MagickResetIterator(wand.wand);
+    }
+    while unsafe { MagickNextImage(wand.wand) } == MagickBooleanType_MagickTrue {
+        f(wand)?;
+    }
+    Ok(())
+}
+
+/// Crops, resizes, fits and rotates every frame, and encodes them as an animation in `format`.
+/// `Smart` crops are centred, as the content of the frames changes.
+pub fn process_frames(
+    frames: &MagickWand,
+    request: &ProcessImageRequest,
+    resize: &Resize,
+    format: ImageFormat,
+    options: &EncodeOptions,
+    profiles: &Profiles,
+) -> Result<Vec<u8>, opencv::Error> {
+    let (width, height) = unsafe {
+        MagickSetIteratorIndex(frames.wand, 0);
+        (
+            MagickGetImageWidth(frames.wand) as i32,
+            MagickGetImageHeight(frames.wand) as i32,
+        )
+    };
+    let region = match &request.crop {
+        Some(crop) => Some(get_crop_region(crop, width, height)?),
+        None => None,
+    };
+    let (width, height) = region
+        .as_ref()
+        .map_or((width, height), |region| (region.width, region.height));
+    let layout = get_layout(width, height, resize)?;
+    debug!(
+        "Processing {} frames. Crop: {:?}. Cropped size: {}x{}. Final layout: {:?}. Rotation: {:?}",
+        frame_count(frames),
+        region,
+        width,
+        height,
+        layout,
+        request.rotation
+    );
+    let degrees = match request.rotation {
+        None => 0.0,
+        Some(Rotation::R90) => 270.0,
+        Some(Rotation::R180) => 180.0,
+        Some(Rotation::R270) => 90.0,
+    };
+    let mut background = PixelWand::new();
+    background
+        .set_color("transparent")
+        .map_err(MagickError::from)?;
+    let mut pad_background = PixelWand::new();
+    pad_background
+        .set_color(&request.background.to_string())
+        .map_err(MagickError::from)?;
+    for_each_frame(frames, |frame| {
+        unsafe {
+            if let Some(region) = &region {
+                MagickCropImage(
+                    frame.wand,
+                    region.width as usize,
+                    region.height as usize,
+                    region.x as isize,
+                    region.y as isize,
+                );
+                MagickSetImagePage(
+                    frame.wand,
+                    region.width as usize,
+                    region.height as usize,
+                    0,
+                    0,
+                );
+            }
+            MagickResizeImage(
+                frame.wand,
+                layout.width as usize,
+                layout.height as usize,
+                resize_filter(
+                    resize.filter,
+                    (
+                        MagickGetImageWidth(frame.wand) as i32,
+                        MagickGetImageHeight(frame.wand) as i32,
+                    ),
+                    (layout.width, layout.height),
+                ),
+            );
+            if let Some(crop) = &layout.crop {
+                MagickCropImage(
+                    frame.wand,
+                    crop.width as usize,
+                    crop.height as usize,
+                    crop.x as isize,
+                    crop.y as isize,
+                );
+            }
+            if let Some(canvas) = &layout.pad {
+                MagickSetImageBackgroundColor(frame.wand, pad_background.wand);
+                // Negative offsets move the image right and down on the canvas.
+                MagickExtentImage(
+                    frame.wand,
+                    canvas.width as usize,
+                    canvas.height as usize,
+                    -canvas.x as isize,
+                    -canvas.y as isize,
+                );
+            }
+            if degrees != 0.0 {
+                MagickRotateImage(frame.wand, background.wand, degrees);
+            }
+            if let Some(angle) = request.angle {
+                let width = MagickGetImageWidth(frame.wand);
+                let height = MagickGetImageHeight(frame.wand);
+                // ImageMagick rotates clockwise and always grows the canvas.
+                MagickRotateImage(frame.wand, pad_background.wand, -angle);
+                let rotated = (
+                    MagickGetImageWidth(frame.wand) as i32,
+                    MagickGetImageHeight(frame.wand) as i32,
+                );
+                if !request.expand {
+                    let x = (rotated.0 as isize - width as isize) / 2;
+                    let y = (rotated.1 as isize - height as isize) / 2;
+                    MagickCropImage(frame.wand, width, height, x, y);
+                } else {
+                    let limited = limit_pixels(rotated.0, rotated.1, resize.max_pixels);
+                    if limited != rotated {
+                        MagickResizeImage(
+                            frame.wand,
+                            limited.0 as usize,
+                            limited.1 as usize,
+                            resize_filter(resize.filter, rotated, limited),
+                        );
+                    }
+                }
+            }
+            if request.flip {
+                MagickFlipImage(frame.wand);
+            }
+            if request.flop {
+                MagickFlopImage(frame.wand);
+            }
+            MagickSetImagePage(
+                frame.wand,
+                MagickGetImageWidth(frame.wand),
+                MagickGetImageHeight(frame.wand),
+                0,
+                0,
+            );
+            MagickSetImageCompressionQuality(frame.wand, options.quality as usize);
+        }
+        Ok(())
+    })?;
+    set_encoder_options(frames, format, options);
+    write_frames(frames, format, profiles).map_err(|e| e.into())
+}
+
+/// Encodes every frame of the wand, unlike `MagickWand::write_image_blob` which only encodes
+/// the current one. The profiles are written once, with the first frame.
+pub fn write_frames(
+    frames: &MagickWand,
+    format: ImageFormat,
+    profiles: &Profiles,
+) -> Result<Vec<u8>, MagickError> {
+    debug!("Encoding frames to: {}", format);
+    let format = CString::new(format.to_string()).expect("Format has no NUL bytes");
+    let empty = Profiles::default();
+    let mut first = true;
+    for_each_frame(frames, |frame| {
+        if first {
+            profiles.apply(frame);
+            first = false;
+        } else {
+            empty.apply(frame);
+        }
+        unsafe {
+            MagickSetImageFormat(frame.wand, format.as_ptr());
+        }
+        Ok(())
+    })?;
+    unsafe {
+        MagickResetIterator(frames.wand);
+        let mut length: usize = 0;
+        let blob = MagickGetImagesBlob(frames.wand, &mut length);
+        if blob.is_null() {
+            return Err("Failed to encode the frames of the image".into());
+        }
+        let body = std::slice::from_raw_parts(blob, length).to_vec();
+        MagickRelinquishMemory(blob as *mut c_void);
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webp_header(flags: u8) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header.extend_from_slice(b"WEBPVP8X");
+        header.extend_from_slice(&[10, 0, 0, 0]);
+        header.push(flags);
+        header.extend_from_slice(&[0; 9]);
+        header
+    }
+
+    #[test]
+    fn test_is_gif() {
+        assert!(is_gif(b"GIF89a\x01\x00\x01\x00"));
+        assert!(is_gif(b"GIF87a\x01\x00\x01\x00"));
+        assert!(!is_gif(b"\x89PNG\r\n\x1a\n"));
+        assert!(!is_gif(b"GIF"));
+    }
+
+    #[test]
+    fn test_is_animated_webp() {
+        assert!(is_animated_webp(&webp_header(0x02)));
+        assert!(is_animated_webp(&webp_header(0x12)));
+        assert!(!is_animated_webp(&webp_header(0x10)));
+        let mut lossy = webp_header(0x02);
+        lossy[12..16].copy_from_slice(b"VP8 ");
+        assert!(!is_animated_webp(&lossy));
+        assert!(!is_animated_webp(b"RIFF"));
+    }
+}
@@ -0,0 +1,502 @@
+/// Tag of the orientation of the image in the first image file directory.
+const ORIENTATION_TAG: u16 = 0x0112;
+/// Tag of the copyright notice of the image in the first image file directory.
+const COPYRIGHT_TAG: u16 = 0x8298;
+/// TIFF field type of NUL terminated ASCII strings.
+const ASCII_TYPE: u16 = 2;
+/// TIFF field type of 16 bit unsigned integers.
+const SHORT_TYPE: u16 = 3;
+/// Header of the EXIF metadata in JPEG `APP1` segments and in ImageMagick profiles.
+pub const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// How the stored pixels have to be transformed for the image to be displayed upright, as
+/// the values 1 to 8 of the EXIF orientation tag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    Normal,
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    fn from_tag(value: u16) -> Option<Orientation> {
+        match value {
+            1 => Some(Orientation::Normal),
+            2 => Some(Orientation::FlipHorizontal),
+            3 => Some(Orientation::Rotate180),
+            4 => Some(Orientation::FlipVertical),
+            5 => Some(Orientation::Transpose),
+            6 => Some(Orientation::Rotate90),
+            7 => Some(Orientation::Transverse),
+            8 => Some(Orientation::Rotate270),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of an image file directory, whose value is read from the TIFF structure it
+/// belongs to.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub tag: u16,
+    pub field_type: u16,
+    pub count: u32,
+    /// Offset of the value, or of the offset of the value when it does not fit in 4 bytes.
+    value_offset: usize,
+}
+
+/// The TIFF structure EXIF metadata is stored in.
+pub struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Tiff<'a>> {
+        let big_endian = match data.get(0..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Entries of the first image file directory, which describes the main image.
+    pub fn entries(&self) -> Option<Vec<Entry>> {
+        let offset = self.u32_at(4)? as usize;
+        let count = self.u16_at(offset)? as usize;
+        (0..count)
+            .map(|index| {
+                let entry = offset + 2 + index * 12;
+                Some(Entry {
+                    tag: self.u16_at(entry)?,
+                    field_type: self.u16_at(entry + 2)?,
+                    count: self.u32_at(entry + 4)?,
+                    value_offset: entry + 8,
+                })
+            })
+            .collect()
+    }
+
+    /// Raw bytes of the value of the entry.
+    pub fn value(&self, entry: &Entry) -> Option<&'a [u8]> {
+        let size = match entry.field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => return None,
+        } * entry.count as usize;
+        let offset = if size <= 4 {
+            entry.value_offset
+        } else {
+            self.u32_at(entry.value_offset)? as usize
+        };
+        self.data.get(offset..offset.checked_add(size)?)
+    }
+
+    fn short(&self, entry: &Entry) -> Option<u16> {
+        if entry.field_type != SHORT_TYPE || entry.count != 1 {
+            return None;
+        }
+        self.u16_at(entry.value_offset)
+    }
+}
+
+/// Payload of the `APP1` segment holding the EXIF metadata of a JPEG image, starting with the
+/// TIFF header.
+pub fn jpeg_exif(buffer: &[u8]) -> Option<&[u8]> {
+    if !buffer.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut offset = 2;
+    loop {
+        let marker = buffer.get(offset..offset + 2)?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        match marker[1] {
+            // Fill bytes before a marker.
+            0xFF => {
+                offset += 1;
+                continue;
+            }
+            // Markers without a payload.
+            0x01 | 0xD0..=0xD8 => {
+                offset += 2;
+                continue;
+            }
+            // Metadata segments all come before the image data.
+            0xD9 | 0xDA => return None,
+            _ => {}
+        }
+        let length = u16::from_be_bytes([*buffer.get(offset + 2)?, *buffer.get(offset + 3)?]);
+        let length = usize::from(length);
+        if length < 2 {
+            return None;
+        }
+        let payload = buffer.get(offset + 4..offset + 2 + length)?;
+        if marker[1] == 0xE1 && payload.starts_with(EXIF_HEADER) {
+            return Some(&payload[EXIF_HEADER.len()..]);
+        }
+        offset += 2 + length;
+    }
+}
+
+/// Data of the `eXIf` chunk holding the EXIF metadata of a PNG image.
+fn png_exif(buffer: &[u8]) -> Option<&[u8]> {
+    if !buffer.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return None;
+    }
+    let mut offset = 8;
+    loop {
+        let length = u32::from_be_bytes([
+            *buffer.get(offset)?,
+            *buffer.get(offset + 1)?,
+            *buffer.get(offset + 2)?,
+            *buffer.get(offset + 3)?,
+        ]) as usize;
+        let kind = buffer.get(offset + 4..offset + 8)?;
+        let data = buffer.get(offset + 8..(offset + 8).checked_add(length)?)?;
+        match kind {
+            b"eXIf" => return Some(data),
+            b"IEND" => return None,
+            // The CRC follows the data.
+            _ => offset += 12 + length,
+        }
+    }
+}
+
+/// Data of the `EXIF` chunk holding the EXIF metadata of a WebP image.
+fn webp_exif(buffer: &[u8]) -> Option<&[u8]> {
+    if buffer.get(0..4)? != b"RIFF" || buffer.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut offset = 12;
+    loop {
+        let kind = buffer.get(offset..offset + 4)?;
+        let length = u32::from_le_bytes([
+            *buffer.get(offset + 4)?,
+            *buffer.get(offset + 5)?,
+            *buffer.get(offset + 6)?,
+            *buffer.get(offset + 7)?,
+        ]) as usize;
+        let data = buffer.get(offset + 8..(offset + 8).checked_add(length)?)?;
+        if kind == b"EXIF" {
+            return Some(data);
+        }
+        // Chunks are padded to an even length.
+        offset += 8 + length + length % 2;
+    }
+}
+
+/// Orientation of a JPEG, PNG or WebP image, when its EXIF metadata sets a valid one.
+pub fn orientation(buffer: &[u8]) -> Option<Orientation> {
+    let exif = jpeg_exif(buffer)
+        .or_else(|| png_exif(buffer))
+        .or_else(|| webp_exif(buffer))?;
+    // Some PNG and WebP encoders keep the header of the JPEG segment.
+    let exif = if exif.starts_with(EXIF_HEADER) {
+        &exif[EXIF_HEADER.len()..]
+    } else {
+        exif
+    };
+    let tiff = Tiff::parse(exif)?;
+    let entry = tiff
+        .entries()?
+        .into_iter()
+        .find(|entry| entry.tag == ORIENTATION_TAG)?;
+    Orientation::from_tag(tiff.short(&entry)?)
+}
+
+/// EXIF metadata holding nothing but the copyright notice of `exif`, which starts either with
+/// `EXIF_HEADER` or with the TIFF header. The result starts with `EXIF_HEADER`.
+pub fn copyright_exif(exif: &[u8]) -> Option<Vec<u8>> {
+    let exif = if exif.starts_with(EXIF_HEADER) {
+        &exif[EXIF_HEADER.len()..]
+    } else {
+        exif
+    };
+    let tiff = Tiff::parse(exif)?;
+    let entry = tiff
+        .entries()?
+        .into_iter()
+        .find(|entry| entry.tag == COPYRIGHT_TAG && entry.field_type == ASCII_TYPE)?;
+    let mut copyright = tiff.value(&entry)?.to_vec();
+    if copyright.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    if copyright.last() != Some(&0) {
+        copyright.push(0);
+    }
+    let count = copyright.len() as u32;
+    let mut data = EXIF_HEADER.to_vec();
+    data.extend_from_slice(b"II*\0");
+    data.extend_from_slice(&8u32.to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&COPYRIGHT_TAG.to_le_bytes());
+    data.extend_from_slice(&ASCII_TYPE.to_le_bytes());
+    data.extend_from_slice(&count.to_le_bytes());
+    if copyright.len() <= 4 {
+        copyright.resize(4, 0);
+        data.extend_from_slice(&copyright);
+        data.extend_from_slice(&0u32.to_le_bytes());
+    } else {
+        // The value follows the directory and the offset of the next one, which there is not.
+        data.extend_from_slice(&26u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&copyright);
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TIFF structure with a single entry, whose value fits in 4 bytes, in its first image
+    /// file directory.
+    fn tiff(big_endian: bool, tag: u16, field_type: u16, value: &[u8]) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut data = if big_endian {
+            b"MM\0*".to_vec()
+        } else {
+            b"II*\0".to_vec()
+        };
+        data.extend_from_slice(&u32_bytes(8));
+        data.extend_from_slice(&u16_bytes(1));
+        data.extend_from_slice(&u16_bytes(tag));
+        data.extend_from_slice(&u16_bytes(field_type));
+        let count = if field_type == 2 { value.len() } else { 1 };
+        data.extend_from_slice(&u32_bytes(count as u32));
+        let mut inline = value.to_vec();
+        inline.resize(4, 0);
+        data.extend_from_slice(&inline);
+        data.extend_from_slice(&u32_bytes(0));
+        data
+    }
+
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        // A JFIF segment, which comes before the EXIF one in many files.
+        data.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x07]);
+        data.extend_from_slice(b"JFIF\0");
+        data.extend_from_slice(&[0xFF, 0xE1]);
+        data.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        data.extend_from_slice(b"Exif\0\0");
+        data.extend_from_slice(tiff);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn test_orientations() {
+        let orientations = [
+            Orientation::Normal,
+            Orientation::FlipHorizontal,
+            Orientation::Rotate180,
+            Orientation::FlipVertical,
+            Orientation::Transpose,
+            Orientation::Rotate90,
+            Orientation::Transverse,
+            Orientation::Rotate270,
+        ];
+        for (index, expected) in orientations.iter().enumerate() {
+            let value = index as u16 + 1;
+            let little = tiff(false, ORIENTATION_TAG, SHORT_TYPE, &value.to_le_bytes());
+            let big = tiff(true, ORIENTATION_TAG, SHORT_TYPE, &value.to_be_bytes());
+            assert_eq!(orientation(&jpeg(&little)), Some(*expected));
+            assert_eq!(orientation(&jpeg(&big)), Some(*expected));
+        }
+    }
+
+    fn png(tiff: &[u8]) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, chunk) in [
+            (&b"IHDR"[..], &[0; 13][..]),
+            (&b"eXIf"[..], tiff),
+            (&b"IEND"[..], &[][..]),
+        ]
+        .iter()
+        {
+            data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            data.extend_from_slice(kind);
+            data.extend_from_slice(chunk);
+            // CRCs are not checked.
+            data.extend_from_slice(&[0; 4]);
+        }
+        data
+    }
+
+    fn webp(exif: &[u8]) -> Vec<u8> {
+        let mut chunks = Vec::new();
+        for (kind, chunk) in [
+            (&b"VP8X"[..], &[0x08; 10][..]),
+            (&b"VP8L"[..], &b"odd"[..]),
+            (&b"EXIF"[..], exif),
+        ]
+        .iter()
+        {
+            chunks.extend_from_slice(kind);
+            chunks.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            chunks.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1 {
+                chunks.push(0);
+            }
+        }
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+        data.extend_from_slice(b"WEBP");
+        data.extend_from_slice(&chunks);
+        data
+    }
+
+    #[test]
+    fn test_png_and_webp_orientations() {
+        let data = tiff(false, ORIENTATION_TAG, SHORT_TYPE, &6u16.to_le_bytes());
+        let mut with_header = EXIF_HEADER.to_vec();
+        with_header.extend_from_slice(&data);
+        assert_eq!(orientation(&png(&data)), Some(Orientation::Rotate90));
+        assert_eq!(orientation(&webp(&data)), Some(Orientation::Rotate90));
+        assert_eq!(
+            orientation(&webp(&with_header)),
+            Some(Orientation::Rotate90)
+        );
+        let png = png(&data);
+        // Truncated before the end of the `eXIf` data.
+        for length in 0..png.len() - 16 {
+            assert_eq!(orientation(&png[..length]), None, "{}", length);
+        }
+    }
+
+    #[test]
+    fn test_invalid_orientations() {
+        let invalid = [
+            tiff(false, ORIENTATION_TAG, SHORT_TYPE, &0u16.to_le_bytes()),
+            tiff(false, ORIENTATION_TAG, SHORT_TYPE, &9u16.to_le_bytes()),
+            tiff(false, ORIENTATION_TAG, 4, &6u32.to_le_bytes()),
+            tiff(false, 0x0110, SHORT_TYPE, &6u16.to_le_bytes()),
+        ];
+        for data in invalid.iter() {
+            assert_eq!(orientation(&jpeg(data)), None);
+        }
+    }
+
+    #[test]
+    fn test_malformed_jpeg() {
+        let data = jpeg(&tiff(
+            false,
+            ORIENTATION_TAG,
+            SHORT_TYPE,
+            &6u16.to_le_bytes(),
+        ));
+        for length in 0..data.len() - 6 {
+            assert_eq!(orientation(&data[..length]), None, "{}", length);
+        }
+        assert_eq!(orientation(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(orientation(&[0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x02]), None);
+        assert_eq!(orientation(&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn test_value() {
+        let data = tiff(true, 0x8298, 2, b"ab\0");
+        let tiff = Tiff::parse(&data).expect("Invalid TIFF header");
+        let entries = tiff.entries().expect("Invalid directory");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tag, 0x8298);
+        assert_eq!(tiff.value(&entries[0]), Some(&b"ab\0"[..]));
+    }
+
+    fn copyright(exif: &[u8]) -> Option<Vec<u8>> {
+        let tiff = Tiff::parse(&exif[EXIF_HEADER.len()..])?;
+        let entries = tiff.entries()?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tag, COPYRIGHT_TAG);
+        tiff.value(&entries[0]).map(|value| value.to_vec())
+    }
+
+    #[test]
+    fn test_copyright_exif() {
+        let short =
+            copyright_exif(&tiff(true, COPYRIGHT_TAG, ASCII_TYPE, b"ab\0")).expect("No copyright");
+        assert_eq!(copyright(&short), Some(b"ab\0".to_vec()));
+        let unterminated = tiff(false, COPYRIGHT_TAG, ASCII_TYPE, b"abcd");
+        let mut exif = EXIF_HEADER.to_vec();
+        exif.extend_from_slice(&unterminated);
+        let unterminated = copyright_exif(&exif).expect("No copyright");
+        assert_eq!(copyright(&unterminated), Some(b"abcd\0".to_vec()));
+        assert_eq!(copyright_exif(&unterminated), Some(unterminated.clone()));
+    }
+
+    #[test]
+    fn test_copyright_exif_drops_other_entries() {
+        // A directory with a pointer to GPS coordinates and a copyright notice stored after it.
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&0x8825u16.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&64u32.to_le_bytes());
+        data.extend_from_slice(&COPYRIGHT_TAG.to_le_bytes());
+        data.extend_from_slice(&ASCII_TYPE.to_le_bytes());
+        data.extend_from_slice(&12u32.to_le_bytes());
+        data.extend_from_slice(&38u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(b"Photographer");
+        let exif = copyright_exif(&data).expect("No copyright");
+        assert_eq!(copyright(&exif), Some(b"Photographer\0".to_vec()));
+    }
+
+    #[test]
+    fn test_no_copyright_exif() {
+        let invalid = [
+            tiff(false, COPYRIGHT_TAG, ASCII_TYPE, b"\0"),
+            tiff(false, COPYRIGHT_TAG, SHORT_TYPE, &1u16.to_le_bytes()),
+            tiff(false, ORIENTATION_TAG, SHORT_TYPE, &1u16.to_le_bytes()),
+            b"Exif\0\0".to_vec(),
+        ];
+        for data in invalid.iter() {
+            assert_eq!(copyright_exif(data), None);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::commons::errors::*;
+use crate::image_processor::{animation, metadata, svg};
+
+use magick_rust::bindings::{MagickAutoOrientImage, MagickSetImageDepth, MagickSetIteratorIndex};
+use magick_rust::MagickWand;
+
+/// Formats of the original images, detected from their magic bytes.
+#[derive(Debug, PartialEq)]
+pub enum InputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Gif,
+    Tiff,
+    Bmp,
+    Heif,
+    Svg,
+}
+
+/// Major brands of the `ftyp` box of HEIF images, HEIC included.
+const HEIF_BRANDS: [&[u8]; 8] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+impl InputFormat {
+    pub fn detect(buffer: &[u8]) -> Option<InputFormat> {
+        if buffer.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(InputFormat::Jpeg)
+        } else if buffer.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(InputFormat::Png)
+        } else if buffer.len() >= 12 && &buffer[0..4] == b"RIFF" && &buffer[8..12] == b"WEBP" {
+            Some(InputFormat::Webp)
+        } else if animation::is_gif(buffer) {
+            Some(InputFormat::Gif)
+        } else if buffer.starts_with(b"II*\0") || buffer.starts_with(b"MM\0*") {
+            Some(InputFormat::Tiff)
+        } else if buffer.starts_with(b"BM") {
+            Some(InputFormat::Bmp)
+        } else if buffer.len() >= 12
+            && &buffer[4..8] == b"ftyp"
+            && HEIF_BRANDS.contains(&&buffer[8..12])
+        {
+            Some(InputFormat::Heif)
+        } else if svg::is_svg(buffer) {
+            Some(InputFormat::Svg)
+        } else {
+            None
+        }
+    }
+
+    /// Whether images of the format may have several pages. The frames of animations are not
+    /// pages.
+    pub fn has_pages(&self) -> bool {
+        match self {
+            InputFormat::Tiff | InputFormat::Heif => true,
+            _ => false,
+        }
+    }
+
+    /// Whether OpenCV cannot read the format reliably, so it is decoded with ImageMagick.
+    pub fn needs_magick(&self) -> bool {
+        match self {
+            InputFormat::Jpeg | InputFormat::Png | InputFormat::Webp => false,
+            InputFormat::Gif
+            | InputFormat::Tiff
+            | InputFormat::Bmp
+            | InputFormat::Heif
+            | InputFormat::Svg => true,
+        }
+    }
+}
+
+/// Decodes one page of the image with ImageMagick and re-encodes it as an 8 bit PNG, which
+/// OpenCV reads. PNG has no orientation, so with `auto_orient` the page is turned upright as
+/// its metadata says. With `to_srgb` its colours are converted to sRGB, as `convert_to_srgb`
+/// does with `srgb`.
+pub fn decode_page(
+    buffer: &[u8],
+    page: usize,
+    auto_orient: bool,
+    to_srgb: bool,
+    srgb: Option<&[u8]>,
+) -> Result<Vec<u8>, opencv::Error> {
+    let wand = MagickWand::new();
+    wand.read_image_blob(buffer).map_err(MagickError::from)?;
+    let pages = animation::frame_count(&wand);
+    if page >= pages {
+        return Err(InvalidPageError::new(page, pages).into());
+    }
+    debug!("Decoding page {} of {}", page, pages);
+    unsafe {
+        MagickSetIteratorIndex(wand.wand, page as isize);
+    }
+    if to_srgb {
+        metadata::convert_to_srgb(&wand, srgb);
+    }
+    unsafe {
+        MagickSetImageDepth(wand.wand, 8);
+        if auto_orient {
+            MagickAutoOrientImage(wand.wand);
+        }
+    }
+    wand.write_image_blob("png")
+        .map_err(|e| MagickError::from(e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ftyp(brand: &[u8]) -> Vec<u8> {
+        let mut header = vec![0, 0, 0, 24];
+        header.extend_from_slice(b"ftyp");
+        header.extend_from_slice(brand);
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        header
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            InputFormat::detect(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some(InputFormat::Jpeg)
+        );
+        assert_eq!(
+            InputFormat::detect(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"),
+            Some(InputFormat::Png)
+        );
+        assert_eq!(
+            InputFormat::detect(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(InputFormat::Webp)
+        );
+        assert_eq!(InputFormat::detect(b"GIF89a"), Some(InputFormat::Gif));
+        assert_eq!(
+            InputFormat::detect(b"II*\0\x08\0\0\0"),
+            Some(InputFormat::Tiff)
+        );
+        assert_eq!(
+            InputFormat::detect(b"MM\0*\0\0\0\x08"),
+            Some(InputFormat::Tiff)
+        );
+        assert_eq!(InputFormat::detect(b"BM\x36\0\0\0"), Some(InputFormat::Bmp));
+        assert_eq!(InputFormat::detect(&ftyp(b"heic")), Some(InputFormat::Heif));
+        assert_eq!(InputFormat::detect(&ftyp(b"mif1")), Some(InputFormat::Heif));
+        assert_eq!(
+            InputFormat::detect(b"<?xml version=\"1.0\"?><svg/>"),
+            Some(InputFormat::Svg)
+        );
+    }
+
+    #[test]
+    fn test_has_pages() {
+        assert!(InputFormat::Tiff.has_pages());
+        assert!(InputFormat::Heif.has_pages());
+        assert!(!InputFormat::Gif.has_pages());
+        assert!(!InputFormat::Jpeg.has_pages());
+    }
+
+    #[test]
+    fn test_detect_unsupported() {
+        assert_eq!(InputFormat::detect(b""), None);
+        assert_eq!(InputFormat::detect(b"<html></html>"), None);
+        assert_eq!(InputFormat::detect(&ftyp(b"isom")), None);
+        assert_eq!(InputFormat::detect(b"RIFF\0\0\0\0WAVEfmt "), None);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::commons::*;
+use crate::image_processor::{exif, EncodeOptions};
+
+use magick_rust::bindings::{
+    ColorspaceType_CMYKColorspace, ColorspaceType_sRGBColorspace, MagickBooleanType_MagickTrue,
+    MagickGetImageColorspace, MagickGetImageProfile, MagickPingImageBlob, MagickProfileImage,
+    MagickRelinquishMemory, MagickSetImageArtifact, MagickSetImageProfile, MagickSetIteratorIndex,
+    MagickStripImage, MagickTransformImageColorspace,
+};
+use magick_rust::MagickWand;
+use std::ffi::CString;
+use std::os::raw::c_void;
+
+/// PNG chunks ImageMagick leaves out once an image is stripped, but for the ones of the profiles.
+const EXCLUDED_PNG_CHUNKS: &str = "bKGD,caNv,cHRM,gAMA,iTXt,pHYs,sRGB,tEXt,zCCP,zTXt,date";
+
+/// Metadata and colour space of the original image, read from its headers.
+#[derive(Debug, Default)]
+pub struct Original {
+    icc: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+    cmyk: bool,
+}
+
+impl Original {
+    /// Reads the headers of the original image. Metadata ImageMagick cannot read is dropped
+    /// rather than failing the request.
+    pub fn read(buffer: &[u8]) -> Original {
+        let wand = MagickWand::new();
+        let pinged = unsafe {
+            MagickPingImageBlob(wand.wand, buffer.as_ptr() as *const c_void, buffer.len())
+        };
+        if pinged != MagickBooleanType_MagickTrue {
+            debug!("Failed to read the metadata of the image");
+            return Original::default();
+        }
+        unsafe {
+            MagickSetIteratorIndex(wand.wand, 0);
+        }
+        Original {
+            icc: profile(&wand, "icc"),
+            exif: profile(&wand, "exif"),
+            cmyk: is_cmyk(&wand),
+        }
+    }
+
+    /// Whether the colours have to be converted to sRGB while the image is decoded, as
+    /// `convert_to_srgb` does with `srgb`.
+    pub fn needs_srgb(&self, srgb: Option<&[u8]>) -> bool {
+        self.cmyk || (srgb.is_some() && converts(self.icc.as_ref(), self.cmyk))
+    }
+
+    /// Profiles the processed image is written with. Images converted to sRGB are written with
+    /// the configured sRGB profile instead of their own.
+    pub fn profiles(self, options: &EncodeOptions, converted: bool) -> Profiles {
+        let keep_icc = options.metadata != Metadata::StripAll || options.icc == Icc::Preserve;
+        let icc = match &options.srgb_profile {
+            _ if !keep_icc => None,
+            Some(srgb) if converted => Some(srgb.to_vec()),
+            _ if converted => None,
+            _ => self.icc.filter(|icc| is_rgb(icc)),
+        };
+        let exif = match options.metadata {
+            Metadata::KeepCopyright => self.exif.and_then(|exif| exif::copyright_exif(&exif)),
+            _ => None,
+        };
+        Profiles { icc, exif }
+    }
+}
+
+/// Converts the colours of the current image of the wand to sRGB. CMYK images are always
+/// converted, from their ICC profile when they have one and `srgb` is set. Other images are
+/// converted from their ICC profile when `srgb` is set and it is not sRGB already.
+pub fn convert_to_srgb(wand: &MagickWand, srgb: Option<&[u8]>) {
+    let cmyk = is_cmyk(wand);
+    if let Some(srgb) = srgb.filter(|_| converts(profile(wand, "icc").as_ref(), cmyk)) {
+        debug!("Converting the ICC profile of the image to sRGB");
+        let name = CString::new("icc").expect("Profile name has no NUL bytes");
+        unsafe {
+            MagickProfileImage(
+                wand.wand,
+                name.as_ptr(),
+                srgb.as_ptr() as *const c_void,
+                srgb.len(),
+            );
+        }
+    }
+    if is_cmyk(wand) {
+        debug!("Converting the CMYK image to sRGB");
+        unsafe {
+            MagickTransformImageColorspace(wand.wand, ColorspaceType_sRGBColorspace);
+        }
+    }
+}
+
+/// Metadata of the original image which is written to the processed one.
+#[derive(Debug, Default, PartialEq)]
+pub struct Profiles {
+    /// ICC colour profile, only kept for RGB images.
+    pub icc: Option<Vec<u8>>,
+    /// EXIF metadata with nothing but the copyright notice, starting with `exif::EXIF_HEADER`.
+    pub exif: Option<Vec<u8>>,
+}
+
+impl Profiles {
+    pub fn is_empty(&self) -> bool {
+        self.icc.is_none() && self.exif.is_none()
+    }
+
+    /// Replaces the metadata of the current image of the wand with the profiles, before
+    /// ImageMagick encodes it.
+    pub fn apply(&self, wand: &MagickWand) {
+        unsafe {
+            MagickStripImage(wand.wand);
+        }
+        if self.is_empty() {
+            return;
+        }
+        // Stripping also makes ImageMagick leave the chunks of the profiles out of PNG images.
+        set_artifact(wand, "png:exclude-chunk", EXCLUDED_PNG_CHUNKS);
+        let profiles = [("icc", &self.icc), ("exif", &self.exif)];
+        for (name, profile) in profiles.iter() {
+            if let Some(profile) = profile {
+                let name = CString::new(*name).expect("Profile name has no NUL bytes");
+                unsafe {
+                    MagickSetImageProfile(
+                        wand.wand,
+                        name.as_ptr(),
+                        profile.as_ptr() as *const c_void,
+                        profile.len(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn profile(wand: &MagickWand, name: &str) -> Option<Vec<u8>> {
+    let name = CString::new(name).expect("Profile name has no NUL bytes");
+    unsafe {
+        let mut length: usize = 0;
+        let data = MagickGetImageProfile(wand.wand, name.as_ptr(), &mut length);
+        if data.is_null() {
+            return None;
+        }
+        let profile = std::slice::from_raw_parts(data, length).to_vec();
+        MagickRelinquishMemory(data as *mut c_void);
+        Some(profile).filter(|profile| !profile.is_empty())
+    }
+}
+
+fn set_artifact(wand: &MagickWand, key: &str, value: &str) {
+    let key = CString::new(key).expect("Artifact name has no NUL bytes");
+    let value = CString::new(value).expect("Artifact value has no NUL bytes");
+    unsafe {
+        MagickSetImageArtifact(wand.wand, key.as_ptr(), value.as_ptr());
+    }
+}
+
+fn is_cmyk(wand: &MagickWand) -> bool {
+    unsafe { MagickGetImageColorspace(wand.wand) == ColorspaceType_CMYKColorspace }
+}
+
+/// Whether the ICC profile describes RGB colours, which are the ones images are processed in.
+fn is_rgb(icc: &[u8]) -> bool {
+    icc.get(16..20) == Some(&b"RGB "[..])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+/// Colorants of sRGB adapted to D50, the illuminant ICC profiles store colorants under.
+const SRGB_COLORANTS: [(&[u8; 4], [f64; 3]); 3] = [
+    (b"rXYZ", [0.4361, 0.2225, 0.0139]),
+    (b"gXYZ", [0.3851, 0.7169, 0.0971]),
+    (b"bXYZ", [0.1431, 0.0606, 0.7141]),
+];
+/// Tolerance of the comparisons with sRGB, which profiles only store with 16 bit precision.
+const SRGB_TOLERANCE: f64 = 0.001;
+
+/// Data of the tag of the ICC profile with the signature.
+fn tag<'a>(icc: &'a [u8], signature: &[u8]) -> Option<&'a [u8]> {
+    // The tag table follows the 128 bytes of the header.
+    let count = u32_at(icc, 128)?;
+    (0..count).find_map(|index| {
+        let entry = 132 + index * 12;
+        if icc.get(entry..entry + 4)? != signature {
+            return None;
+        }
+        let offset = u32_at(icc, entry + 4)?;
+        icc.get(offset..offset.checked_add(u32_at(icc, entry + 8)?)?)
+    })
+}
+
+fn s15_fixed16_at(data: &[u8], offset: usize) -> Option<f64> {
+    Some(f64::from(u32_at(data, offset)? as u32 as i32) / 65536.0)
+}
+
+/// Value of the tone reproduction curve of a `curv` or `para` tag at `x`, from 0 to 1.
+fn curve_value(curve: &[u8], x: f64) -> Option<f64> {
+    let u16_at = |offset: usize| {
+        let bytes = curve.get(offset..offset + 2)?;
+        Some(f64::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+    };
+    let parameters = |count: usize| {
+        (0..count)
+            .map(|index| s15_fixed16_at(curve, 12 + index * 4))
+            .collect::<Option<Vec<_>>>()
+    };
+    match (curve.get(0..4)?, curve.get(8..10)?) {
+        (b"curv", _) => match u32_at(curve, 8)? {
+            0 => Some(x),
+            1 => Some(x.powf(u16_at(12)? / 256.0)),
+            count => {
+                let position = x * (count - 1) as f64;
+                let index = (position as usize).min(count - 2);
+                let (low, high) = (u16_at(12 + index * 2)?, u16_at(14 + index * 2)?);
+                Some((low + (high - low) * (position - index as f64)) / 65535.0)
+            }
+        },
+        // Parametric curves of the functions sRGB-like profiles use.
+        (b"para", [0, 0]) => Some(x.powf(parameters(1)?[0])),
+        (b"para", [0, 3]) => match parameters(5)?[..] {
+            [gamma, scale, offset, _, threshold] if x >= threshold => {
+                Some((scale * x + offset).powf(gamma))
+            }
+            [_, _, _, slope, _] => Some(slope * x),
+            _ => None,
+        },
+        (b"para", [0, 4]) => match parameters(7)?[..] {
+            [gamma, scale, offset, _, threshold, added, _] if x >= threshold => {
+                Some((scale * x + offset).powf(gamma) + added)
+            }
+            [_, _, _, slope, _, _, linear_offset] => Some(slope * x + linear_offset),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn srgb_curve_value(x: f64) -> f64 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Whether the RGB ICC profile has the colorants and tone curves of sRGB, whatever it is called.
+fn is_srgb(icc: &[u8]) -> bool {
+    let colorants = SRGB_COLORANTS.iter().all(|(signature, expected)| {
+        tag(icc, &signature[..]).map_or(false, |xyz| {
+            expected.iter().enumerate().all(|(index, expected)| {
+                s15_fixed16_at(xyz, 8 + index * 4)
+                    .map_or(false, |value| (value - expected).abs() <= SRGB_TOLERANCE)
+            })
+        })
+    });
+    let curves = [b"rTRC", b"gTRC", b"bTRC"].iter().all(|signature| {
+        tag(icc, &signature[..]).map_or(false, |curve| {
+            (0..=20).map(|step| f64::from(step) / 20.0).all(|x| {
+                curve_value(curve, x).map_or(false, |value| {
+                    (value - srgb_curve_value(x)).abs() <= SRGB_TOLERANCE
+                })
+            })
+        })
+    });
+    colorants && curves
+}
+
+/// Whether the ICC profile has to be converted to sRGB: it describes the colour space of the
+/// image, and it is not sRGB already.
+fn converts(icc: Option<&Vec<u8>>, cmyk: bool) -> bool {
+    icc.map_or(false, |icc| {
+        let colour_space = if cmyk { &b"CMYK"[..] } else { b"RGB " };
+        icc.get(16..20) == Some(colour_space) && (cmyk || !is_srgb(icc))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_rgb() {
+        let mut rgb = vec![0; 128];
+        rgb[16..20].copy_from_slice(b"RGB ");
+        assert!(is_rgb(&rgb));
+        let mut cmyk = vec![0; 128];
+        cmyk[16..20].copy_from_slice(b"CMYK");
+        assert!(!is_rgb(&cmyk));
+        assert!(!is_rgb(b"RGB "));
+    }
+
+    /// An RGB ICC profile with the colorants and the same tone curve for every channel.
+    fn icc(colorants: &[[f64; 3]; 3], curve: &[u8]) -> Vec<u8> {
+        let mut tags = Vec::new();
+        for (signature, xyz) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().zip(colorants.iter()) {
+            let mut data = b"XYZ \0\0\0\0".to_vec();
+            for value in xyz.iter() {
+                data.extend_from_slice(&((value * 65536.0).round() as i32).to_be_bytes());
+            }
+            tags.push((&signature[..], data));
+        }
+        for signature in [b"rTRC", b"gTRC", b"bTRC"].iter() {
+            tags.push((&signature[..], curve.to_vec()));
+        }
+        let mut icc = vec![0; 128];
+        icc[16..20].copy_from_slice(b"RGB ");
+        icc.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        let mut offset = 132 + tags.len() * 12;
+        for (signature, data) in tags.iter() {
+            icc.extend_from_slice(signature);
+            icc.extend_from_slice(&(offset as u32).to_be_bytes());
+            icc.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            offset += data.len();
+        }
+        for (_, data) in tags.iter() {
+            icc.extend_from_slice(data);
+        }
+        icc
+    }
+
+    fn gamma_curve(gamma: f64) -> Vec<u8> {
+        let mut curve = b"curv\0\0\0\0".to_vec();
+        curve.extend_from_slice(&1u32.to_be_bytes());
+        curve.extend_from_slice(&((gamma * 256.0).round() as u16).to_be_bytes());
+        curve
+    }
+
+    fn srgb_parametric_curve() -> Vec<u8> {
+        let mut curve = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for value in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045].iter() {
+            curve.extend_from_slice(&((value * 65536.0_f64).round() as i32).to_be_bytes());
+        }
+        curve
+    }
+
+    const SRGB: [[f64; 3]; 3] = [
+        [0.4361, 0.2225, 0.0139],
+        [0.3851, 0.7169, 0.0971],
+        [0.1431, 0.0606, 0.7141],
+    ];
+    const ADOBE_RGB: [[f64; 3]; 3] = [
+        [0.6097, 0.3111, 0.0195],
+        [0.2053, 0.6257, 0.0609],
+        [0.1492, 0.0632, 0.7446],
+    ];
+
+    #[test]
+    fn test_is_srgb() {
+        assert!(is_srgb(&icc(&SRGB, &srgb_parametric_curve())));
+        assert!(!is_srgb(&icc(&SRGB, &gamma_curve(2.2))));
+        assert!(!is_srgb(&icc(&ADOBE_RGB, &gamma_curve(2.199_218_75))));
+        assert!(!is_srgb(&icc(&ADOBE_RGB, &srgb_parametric_curve())));
+        assert!(!is_srgb(&[0; 128]));
+    }
+
+    #[test]
+    fn test_converts() {
+        let adobe = icc(&ADOBE_RGB, &gamma_curve(2.199_218_75));
+        let srgb = icc(&SRGB, &srgb_parametric_curve());
+        let mut swop = vec![0; 132];
+        swop[16..20].copy_from_slice(b"CMYK");
+        assert!(converts(Some(&adobe), false));
+        assert!(!converts(Some(&srgb), false));
+        assert!(converts(Some(&swop), true));
+        assert!(!converts(Some(&swop), false));
+        assert!(!converts(Some(&adobe), true));
+        assert!(!converts(None, false));
+    }
+
+    #[test]
+    fn test_builtin_srgb_profile() {
+        let srgb = BUILTIN_SRGB_PROFILE.to_vec();
+        assert_eq!(u32_at(&srgb, 0), Some(srgb.len()));
+        assert_eq!(&srgb[36..40], b"acsp");
+        assert!(is_rgb(&srgb));
+        assert!(is_srgb(&srgb));
+        assert!(!converts(Some(&srgb), false));
+    }
+}
@@ -1,5 +1,18 @@
+mod animation;
+mod exif;
+mod input;
+mod metadata;
+mod svg;
+
 use crate::commons::errors::*;
+use crate::commons::geometry::{
+    best_window, get_crop_region, get_layout, get_rotation, Region, Resize,
+};
+use crate::commons::negotiation::AcceptedFormats;
 use crate::commons::*;
+use exif::Orientation;
+use input::InputFormat;
+use metadata::{Original, Profiles};
 use opencv::core;
 use opencv::imgcodecs;
 use opencv::imgproc;
@@ -7,48 +20,240 @@ use opencv::prelude::*;
 use opencv::types::*;
 
 use magick_rust::bindings::{
-    CompositeOperator_OverCompositeOp, FilterType_PointFilter, MagickTransparentPaintImage,
+    CompositeOperator_OverCompositeOp, FilterType, FilterType_BoxFilter, FilterType_CubicFilter,
+    FilterType_LanczosFilter, FilterType_PointFilter, FilterType_TriangleFilter,
+    InterlaceType_JPEGInterlace, MagickSetImageCompressionQuality, MagickSetInterlaceScheme,
+    MagickSetOption, MagickSetSamplingFactors, MagickTransparentPaintImage,
 };
 use magick_rust::{MagickWand, PixelWand};
+use std::ffi::CString;
+use std::sync::Arc;
+
+pub struct EncodeOptions {
+    pub quality: i32,
+    pub png_quality: u8,
+    pub avif_speed: u8,
+    pub webp: WebpOptions,
+    pub jpeg: JpegOptions,
+    pub watermarked: bool,
+    pub metadata: Metadata,
+    pub icc: Icc,
+    pub srgb_profile: Option<Arc<Vec<u8>>>,
+}
+
+impl EncodeOptions {
+    pub fn new(request: &ProcessImageRequest, config: &Configuration) -> Self {
+        EncodeOptions {
+            quality: request.quality,
+            png_quality: config.png_quality,
+            avif_speed: request.speed.unwrap_or(config.avif_speed),
+            webp: request.webp.clone(),
+            jpeg: request.jpeg.or(&config.jpeg),
+            watermarked: !request.watermarks.is_empty(),
+            metadata: request.metadata.unwrap_or(config.metadata),
+            icc: request.icc.unwrap_or(config.icc),
+            srgb_profile: config.srgb.clone(),
+        }
+    }
+
+    /// Part of the cache key, so images are told apart when the configuration changes.
+    pub fn canonical(&self) -> String {
+        let srgb = self
+            .srgb_profile
+            .as_ref()
+            .map_or("none".to_string(), |profile| {
+                let mut hasher = sha1::Sha1::new();
+                hasher.update(&profile[..]);
+                hasher.digest().to_string()
+            });
+        format!(
+            "png_quality={};avif_speed={};jpeg={};metadata={:?};icc={:?};srgb={}",
+            self.png_quality,
+            self.avif_speed,
+            self.jpeg.canonical(),
+            self.metadata,
+            self.icc,
+            srgb
+        )
+    }
+
+    /// Images ImageMagick encodes, for metadata or options OpenCV lacks, are processed as PNG.
+    /// So are watermarked JPEGs with options, which ImageMagick would drop.
+    fn working_format(&self, format: ImageFormat, profiles: &Profiles) -> ImageFormat {
+        match format {
+            _ if !profiles.is_empty() => ImageFormat::Png,
+            ImageFormat::Webp if self.webp != WebpOptions::default() => ImageFormat::Png,
+            ImageFormat::Jpeg if self.jpeg.subsampling == Some(Subsampling::Yuv444) => {
+                ImageFormat::Png
+            }
+            ImageFormat::Jpeg if self.watermarked && self.jpeg != JpegOptions::default() => {
+                ImageFormat::Png
+            }
+            format => format.working_format(),
+        }
+    }
+}
+
+/// `body` is encoded in `working_format` until `encode_output` turns it into `format`.
+pub struct EncodedImage {
+    pub body: Vec<u8>,
+    pub format: ImageFormat,
+    pub working_format: ImageFormat,
+    pub profiles: Profiles,
+}
 
 pub fn pre_process_image(
     buffer: &[u8],
-    rotation: Option<Rotation>,
-    size: &Size,
-    format: ImageFormat,
-    quality: i32,
-    png_quality: u8,
-) -> Result<Vec<u8>, opencv::Error> {
+    request: &ProcessImageRequest,
+    resize: &Resize,
+    accepted: &AcceptedFormats,
+    options: &EncodeOptions,
+) -> Result<EncodedImage, opencv::Error> {
+    let ProcessImageRequest {
+        format,
+        rotation,
+        page,
+        crop,
+        ..
+    } = request;
+    let input_format = InputFormat::detect(buffer).ok_or_else(UnsupportedFormatError::default)?;
+    debug!("Input format: {:?}", input_format);
+    if *page != 0 && !input_format.has_pages() {
+        return Err(InvalidPageError::single(*page).into());
+    }
+    // SVG images have no profiles, and are only read by ImageMagick once they are sanitised.
+    let original = match input_format {
+        InputFormat::Svg => Original::default(),
+        _ => Original::read(buffer),
+    };
+    let srgb = match options.icc {
+        Icc::Convert => options.srgb_profile.as_ref().map(|profile| &profile[..]),
+        Icc::Preserve => None,
+    };
+    // OpenCV neither converts colours nor reads CMYK images right, so ImageMagick decodes them.
+    let to_srgb = original.needs_srgb(srgb);
+    let profiles = original.profiles(options, to_srgb);
+    // OpenCV ignores the orientation of the images it decodes unchanged.
+    let orientation = match input_format {
+        InputFormat::Jpeg | InputFormat::Png | InputFormat::Webp
+            if request.auto_orient && !to_srgb =>
+        {
+            exif::orientation(buffer)
+        }
+        _ => None,
+    };
+    let still;
+    let buffer = match animation::decode(buffer)? {
+        Some(animation::Decoded::Frames(frames)) => {
+            if to_srgb {
+                animation::for_each_frame(&frames, |frame| {
+                    metadata::convert_to_srgb(frame, srgb);
+                    Ok(())
+                })?;
+            }
+            match format.negotiate_animated(accepted) {
+                Some(format) => {
+                    let body = animation::process_frames(
+                        &frames, request, resize, format, options, &profiles,
+                    )?;
+                    return Ok(EncodedImage {
+                        body,
+                        format,
+                        working_format: format,
+                        profiles,
+                    });
+                }
+                None => {
+                    still = animation::first_frame(&frames)?;
+                    &still[..]
+                }
+            }
+        }
+        Some(animation::Decoded::Still(png)) => {
+            still = png;
+            &still[..]
+        }
+        // Crops are in the coordinates of the intrinsic size of the image.
+        None if input_format == InputFormat::Svg && crop.is_some() => {
+            still = svg::rasterise(buffer, &Size::default(), Fit::Contain, resize.max_pixels)?;
+            &still[..]
+        }
+        None if input_format == InputFormat::Svg => {
+            still = svg::rasterise(buffer, &resize.size, resize.fit, resize.max_pixels)?;
+            &still[..]
+        }
+        None if input_format.needs_magick() || to_srgb => {
+            still = input::decode_page(buffer, *page, request.auto_orient, to_srgb, srgb)?;
+            &still[..]
+        }
+        None => buffer,
+    };
     let mat_buf = core::Mat::from_slice(buffer)?;
-    debug!("Resizing image to {:?}", size);
+    debug!("Resizing image to {:?}", resize.size);
     let src_mat = imgcodecs::imdecode(&mat_buf, imgcodecs::IMREAD_UNCHANGED)?;
-    let resized = if size.height.is_none() && size.width.is_none() {
-        src_mat
-    } else {
-        resize_image(&src_mat, &size)?
+    let src_mat = match orientation {
+        Some(orientation) => {
+            debug!("Orienting image: {:?}", orientation);
+            orient_image(src_mat, orientation)?
+        }
+        None => src_mat,
+    };
+    let src_mat = match crop {
+        Some(crop) => {
+            let region = get_crop_region(crop, src_mat.cols()?, src_mat.rows()?)?;
+            debug!("Cropping image to {:?}", region);
+            roi(&src_mat, &region)?
+        }
+        None => src_mat,
     };
-    let enc_quality = match format {
-        ImageFormat::Png => i32::from(png_quality),
-        _ => quality,
+    let format = format.negotiate(accepted, src_mat.channels()? == 4);
+    let working_format = options.working_format(format, &profiles);
+    let resized = fit_image(src_mat, resize, &request.background)?;
+    let enc_quality = match working_format {
+        ImageFormat::Png => i32::from(options.png_quality),
+        _ => options.quality,
     };
 
     debug!("Rotating image to {:?}", rotation);
-    let image = if let Some(rotation) = rotation {
+    let image = if let Some(rotation) = *rotation {
         rotate_image(&resized, rotation)?
     } else {
         resized
     };
+    let image = match request.angle {
+        Some(angle) => {
+            debug!("Rotating image by {} degrees", angle);
+            rotate_image_by(
+                image,
+                angle,
+                request.expand,
+                resize.max_pixels,
+                &request.background,
+            )?
+        }
+        None => image,
+    };
+    let image = flip_image(image, request.flip, request.flop)?;
+
+    Ok(EncodedImage {
+        body: encode_mat(&image, working_format, enc_quality, &options.jpeg)?,
+        format,
+        working_format,
+        profiles,
+    })
+}
 
-    let quality = get_encode_params(format, enc_quality as i32);
+fn encode_mat(
+    image: &core::Mat,
+    format: ImageFormat,
+    quality: i32,
+    jpeg: &JpegOptions,
+) -> Result<Vec<u8>, opencv::Error> {
+    let params = get_encode_params(format, quality, jpeg)?;
     let mut rs_buf = VectorOfuchar::new();
 
     debug!("Encoding to: {}", format);
-    imgcodecs::imencode(
-        format!(".{}", format).as_str(),
-        &image,
-        &mut rs_buf,
-        &quality,
-    )?;
+    imgcodecs::imencode(format!(".{}", format).as_str(), image, &mut rs_buf, &params)?;
     Ok(rs_buf.to_vec())
 }
 
@@ -57,12 +262,25 @@ pub fn apply_watermark(
     wm_buffer: &[u8],
     watermark: &Watermark,
     format: ImageFormat,
+    profiles: &Profiles,
+    options: &EncodeOptions,
 ) -> Result<Vec<u8>, MagickError> {
     debug!("Applying watermark: {:?}", watermark);
     let wand = MagickWand::new();
     wand.read_image_blob(img)?;
     let wand_wm = MagickWand::new();
-    wand_wm.read_image_blob(wm_buffer)?;
+    if svg::is_svg(wm_buffer) {
+        // A watermark never needs more pixels than the image it is applied on.
+        let max_pixels = wand.get_image_width() as u64 * wand.get_image_height() as u64;
+        let rasterised = svg::rasterise(wm_buffer, &watermark.size, Fit::Contain, max_pixels)
+            .map_err(|e| MagickError::from(e.message.as_str()))?;
+        wand_wm.read_image_blob(&rasterised)?;
+    } else {
+        wand_wm.read_image_blob(wm_buffer)?;
+        // Watermarks are composed in sRGB, whatever the policy of the image.
+        let srgb = options.srgb_profile.as_ref().map(|profile| &profile[..]);
+        metadata::convert_to_srgb(&wand_wm, srgb);
+    }
     let wm_width = wand_wm.get_image_width() as i32;
     let wm_height = wand_wm.get_image_height() as i32;
     let (wm_target_width, wm_target_height) =
@@ -71,7 +289,11 @@ pub fn apply_watermark(
     wand_wm.resize_image(
         wm_target_width as usize,
         wm_target_height as usize,
-        FilterType_PointFilter,
+        resize_filter(
+            watermark.filter,
+            (wm_width, wm_height),
+            (wm_target_width, wm_target_height),
+        ),
     );
     let (left, top, right, bottom) = get_watermark_borders(
         wand.get_image_width() as i32,
@@ -91,6 +313,20 @@ pub fn apply_watermark(
         MagickTransparentPaintImage(wand_wm.wand, pixel_wand.wand, watermark.alpha, 0.0, 1);
     }
 
+    if animation::frame_count(&wand) > 1 {
+        animation::for_each_frame(&wand, |frame| {
+            frame
+                .compose_images(
+                    &wand_wm,
+                    CompositeOperator_OverCompositeOp,
+                    true,
+                    left as isize,
+                    top as isize,
+                )
+                .map_err(|e| e.into())
+        })?;
+        return animation::write_frames(&wand, format, profiles);
+    }
     wand.compose_images(
         &wand_wm,
         CompositeOperator_OverCompositeOp,
@@ -98,10 +334,98 @@ pub fn apply_watermark(
         left as isize,
         top as isize,
     )?;
+    profiles.apply(&wand);
     wand.write_image_blob(format!("{}", format).as_str())
         .map_err(|e| e.into())
 }
 
+pub fn encode_output(
+    buffer: Vec<u8>,
+    working_format: ImageFormat,
+    format: ImageFormat,
+    options: &EncodeOptions,
+    profiles: &Profiles,
+) -> Result<Vec<u8>, opencv::Error> {
+    // Stills with profiles are processed as PNG, which OpenCV writes without them, while
+    // animations are written with them already.
+    if working_format == format && (profiles.is_empty() || format != ImageFormat::Png) {
+        return Ok(buffer);
+    }
+    if format == ImageFormat::Jpeg
+        && options.jpeg.subsampling != Some(Subsampling::Yuv444)
+        && profiles.is_empty()
+    {
+        let mat_buf = core::Mat::from_slice(&buffer)?;
+        let image = imgcodecs::imdecode(&mat_buf, imgcodecs::IMREAD_UNCHANGED)?;
+        return encode_mat(&image, format, options.quality, &options.jpeg);
+    }
+    debug!("Encoding to: {}", format);
+    let wand = MagickWand::new();
+    wand.read_image_blob(&buffer)?;
+    let quality = match format {
+        // ImageMagick takes the zlib level of PNG images from the tens of the quality, and the
+        // filter from the units, 5 being adaptive filtering.
+        ImageFormat::Png => usize::from(options.png_quality) * 10 + 5,
+        _ => options.quality as usize,
+    };
+    unsafe {
+        MagickSetImageCompressionQuality(wand.wand, quality);
+    }
+    set_encoder_options(&wand, format, options);
+    profiles.apply(&wand);
+    wand.write_image_blob(format!("{}", format).as_str())
+        .map_err(|e| e.into())
+}
+
+fn set_option(wand: &MagickWand, key: &str, value: &str) {
+    let key = CString::new(key).expect("Option name has no NUL bytes");
+    let value = CString::new(value).expect("Option value has no NUL bytes");
+    unsafe {
+        MagickSetOption(wand.wand, key.as_ptr(), value.as_ptr());
+    }
+}
+
+fn set_encoder_options(wand: &MagickWand, format: ImageFormat, options: &EncodeOptions) {
+    match format {
+        ImageFormat::Avif => set_option(wand, "heic:speed", &options.avif_speed.to_string()),
+        ImageFormat::Jpeg => {
+            let jpeg = &options.jpeg;
+            if jpeg.progressive == Some(true) {
+                unsafe {
+                    MagickSetInterlaceScheme(wand.wand, InterlaceType_JPEGInterlace);
+                }
+            }
+            if jpeg.optimize == Some(true) {
+                set_option(wand, "jpeg:optimize-coding", "true");
+            }
+            let sampling_factors = match jpeg.subsampling {
+                Some(Subsampling::Yuv444) => Some([1.0, 1.0]),
+                Some(Subsampling::Yuv420) => Some([2.0, 2.0]),
+                None => None,
+            };
+            if let Some(factors) = sampling_factors {
+                unsafe {
+                    MagickSetSamplingFactors(wand.wand, factors.len(), factors.as_ptr());
+                }
+            }
+        }
+        ImageFormat::Webp => {
+            let webp = &options.webp;
+            // Near-lossless is a preprocessing step of the lossless encoder.
+            if webp.lossless || webp.near_lossless.is_some() {
+                set_option(wand, "webp:lossless", "true");
+            }
+            if let Some(near_lossless) = webp.near_lossless {
+                set_option(wand, "webp:near-lossless", &near_lossless.to_string());
+            }
+            if let Some(alpha_quality) = webp.alpha_quality {
+                set_option(wand, "webp:alpha-quality", &alpha_quality.to_string());
+            }
+        }
+        _ => {}
+    }
+}
+
 fn rotate_image(img: &core::Mat, rotation: Rotation) -> Result<core::Mat, opencv::Error> {
     let mut result_transpose = core::Mat::default()?;
     let mut result_flip = core::Mat::default()?;
@@ -121,12 +445,57 @@ fn rotate_image(img: &core::Mat, rotation: Rotation) -> Result<core::Mat, opencv
     Ok(result_flip)
 }
 
-fn get_encode_params(f: ImageFormat, q: i32) -> VectorOfint {
+fn orient_image(img: core::Mat, orientation: Orientation) -> Result<core::Mat, opencv::Error> {
+    // Flip codes: 0 flips vertically, 1 horizontally and -1 both ways.
+    let (transpose, flip_code) = match orientation {
+        Orientation::Normal => (false, None),
+        Orientation::FlipHorizontal => (false, Some(1)),
+        Orientation::Rotate180 => (false, Some(-1)),
+        Orientation::FlipVertical => (false, Some(0)),
+        Orientation::Transpose => (true, None),
+        Orientation::Rotate90 => (true, Some(1)),
+        Orientation::Transverse => (true, Some(-1)),
+        Orientation::Rotate270 => (true, Some(0)),
+    };
+    let img = if transpose {
+        let mut result_transpose = core::Mat::default()?;
+        core::transpose(&img, &mut result_transpose)?;
+        result_transpose
+    } else {
+        img
+    };
+    match flip_code {
+        Some(flip_code) => {
+            let mut result_flip = core::Mat::default()?;
+            core::flip(&img, &mut result_flip, flip_code)?;
+            Ok(result_flip)
+        }
+        None => Ok(img),
+    }
+}
+
+fn get_encode_params(
+    f: ImageFormat,
+    q: i32,
+    jpeg: &JpegOptions,
+) -> Result<VectorOfint, opencv::Error> {
     let mut quality = VectorOfint::with_capacity(2);
     match f {
         ImageFormat::Jpeg => {
             quality.push(imgcodecs::IMWRITE_JPEG_QUALITY);
             quality.push(q);
+            if jpeg.progressive == Some(true) {
+                quality.push(imgcodecs::IMWRITE_JPEG_PROGRESSIVE);
+                quality.push(1);
+            }
+            if jpeg.optimize == Some(true) {
+                quality.push(imgcodecs::IMWRITE_JPEG_OPTIMIZE);
+                quality.push(1);
+            }
+            if let Some(interval) = jpeg.restart_interval {
+                quality.push(imgcodecs::IMWRITE_JPEG_RST_INTERVAL);
+                quality.push(i32::from(interval));
+            }
         }
         ImageFormat::Png => {
             quality.push(imgcodecs::IMWRITE_PNG_COMPRESSION);
@@ -136,35 +505,265 @@ fn get_encode_params(f: ImageFormat, q: i32) -> VectorOfint {
             quality.push(imgcodecs::IMWRITE_WEBP_QUALITY);
             quality.push(q);
         }
+        ImageFormat::Gif | ImageFormat::Avif | ImageFormat::Auto => {
+            let reason = format!("{} is not encoded by OpenCV", f);
+            return Err(UnsupportedFormatError::new(&reason).into());
+        }
     };
-    quality
+    Ok(quality)
 }
 
-fn resize_image(img: &core::Mat, size: &Size) -> Result<core::Mat, opencv::Error> {
+fn fit_image(
+    img: core::Mat,
+    resize: &Resize,
+    background: &Color,
+) -> Result<core::Mat, opencv::Error> {
     let original_width = img.cols()?;
     let original_height = img.rows()?;
 
     debug!(
         "Resizing image. Original size: {}x{}. Desired: {:?}",
-        original_width, original_height, size
+        original_width, original_height, resize
     );
 
-    let (target_width, target_height) = get_target_size(original_width, original_height, &size)?;
+    let layout = get_layout(original_width, original_height, resize)?;
+    if layout.crop.is_none()
+        && layout.pad.is_none()
+        && (layout.width, layout.height) == (original_width, original_height)
+    {
+        return Ok(img);
+    }
+
+    debug!("Final layout: {:?}", layout);
+    let mut result = resize_image(&img, layout.width, layout.height, resize.filter)?;
+    if let Some(crop) = layout.crop {
+        let crop = if resize.smart {
+            smart_crop(&result, &crop)?
+        } else {
+            crop
+        };
+        debug!("Cropping resized image to {:?}", crop);
+        result = roi(&result, &crop)?;
+    }
+    if let Some(canvas) = layout.pad {
+        result = pad_image(result, &canvas, background)?;
+    }
+    Ok(result)
+}
 
-    debug!("Final size: {}x{}", target_width, target_height);
+/// Moves a crop to the window with the most edges, which tend to lie on the subject.
+fn smart_crop(img: &core::Mat, crop: &Region) -> Result<Region, opencv::Error> {
+    let mut converted = core::Mat::default()?;
+    let grey = match img.channels()? {
+        3 => {
+            imgproc::cvt_color(img, &mut converted, imgproc::COLOR_BGR2GRAY, 0)?;
+            &converted
+        }
+        4 => {
+            imgproc::cvt_color(img, &mut converted, imgproc::COLOR_BGRA2GRAY, 0)?;
+            &converted
+        }
+        _ => img,
+    };
+    let mut gradient_x = core::Mat::default()?;
+    let mut gradient_y = core::Mat::default()?;
+    let mut edges = core::Mat::default()?;
+    imgproc::sobel(
+        grey,
+        &mut gradient_x,
+        core::CV_32F,
+        1,
+        0,
+        3,
+        1.0,
+        0.0,
+        core::BORDER_DEFAULT,
+    )?;
+    imgproc::sobel(
+        grey,
+        &mut gradient_y,
+        core::CV_32F,
+        0,
+        1,
+        3,
+        1.0,
+        0.0,
+        core::BORDER_DEFAULT,
+    )?;
+    core::magnitude(&gradient_x, &gradient_y, &mut edges)?;
+    // Reducing to a single row sums the columns, and reducing to a single column the rows.
+    let sums = |dimension: i32| -> Result<Vec<u64>, opencv::Error> {
+        let mut sums = core::Mat::default()?;
+        core::reduce(&edges, &mut sums, dimension, core::REDUCE_SUM, core::CV_64F)?;
+        (0..sums.rows()? * sums.cols()?)
+            .map(|index| sums.at::<f64>(index).map(|sum| *sum as u64))
+            .collect()
+    };
+    Ok(Region {
+        x: best_window(&sums(0)?, crop.width as usize) as i32,
+        y: best_window(&sums(1)?, crop.height as usize) as i32,
+        ..*crop
+    })
+}
+
+fn resize_image(
+    img: &core::Mat,
+    width: i32,
+    height: i32,
+    filter: Option<Filter>,
+) -> Result<core::Mat, opencv::Error> {
+    let downscaling = is_downscaling((img.cols()?, img.rows()?), (width, height));
+    let interpolation = match filter {
+        Some(Filter::Nearest) => imgproc::INTER_NEAREST,
+        Some(Filter::Linear) => imgproc::INTER_LINEAR,
+        Some(Filter::Cubic) => imgproc::INTER_CUBIC,
+        Some(Filter::Area) => imgproc::INTER_AREA,
+        Some(Filter::Lanczos) => imgproc::INTER_LANCZOS4,
+        // Averaging the pixels avoids the aliasing other interpolations cause when shrinking.
+        None if downscaling => imgproc::INTER_AREA,
+        None => imgproc::INTER_LINEAR,
+    };
+    debug!(
+        "Resizing to {}x{} with interpolation {}",
+        width, height, interpolation
+    );
     let mut result = core::Mat::default()?;
 
     imgproc::resize(
         img,
         &mut result,
-        core::Size {
-            width: target_width,
-            height: target_height,
-        },
+        core::Size { width, height },
         0f64,
         0f64,
-        imgproc::INTER_LINEAR,
+        interpolation,
+    )?;
+
+    Ok(result)
+}
+
+fn is_downscaling(original: (i32, i32), resized: (i32, i32)) -> bool {
+    i64::from(resized.0) * i64::from(resized.1) < i64::from(original.0) * i64::from(original.1)
+}
+
+/// Same defaults as `resize_image` when no filter was requested.
+fn resize_filter(filter: Option<Filter>, original: (i32, i32), resized: (i32, i32)) -> FilterType {
+    match filter {
+        Some(filter) => magick_filter(filter),
+        None if is_downscaling(original, resized) => FilterType_BoxFilter,
+        None => FilterType_TriangleFilter,
+    }
+}
+
+fn magick_filter(filter: Filter) -> FilterType {
+    match filter {
+        Filter::Nearest => FilterType_PointFilter,
+        Filter::Linear => FilterType_TriangleFilter,
+        Filter::Cubic => FilterType_CubicFilter,
+        Filter::Area => FilterType_BoxFilter,
+        Filter::Lanczos => FilterType_LanczosFilter,
+    }
+}
+
+fn roi(img: &core::Mat, region: &Region) -> Result<core::Mat, opencv::Error> {
+    core::Mat::roi(
+        img,
+        core::Rect {
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        },
+    )
+}
+
+/// Grey images are converted to colour, and get an alpha channel for translucent backgrounds.
+fn with_background(
+    img: core::Mat,
+    background: &Color,
+) -> Result<(core::Mat, core::Scalar), opencv::Error> {
+    let translucent = background.alpha < 255;
+    let conversion = match img.channels()? {
+        1 if translucent => Some(imgproc::COLOR_GRAY2BGRA),
+        1 => Some(imgproc::COLOR_GRAY2BGR),
+        3 if translucent => Some(imgproc::COLOR_BGR2BGRA),
+        _ => None,
+    };
+    let img = match conversion {
+        Some(code) => {
+            let mut converted = core::Mat::default()?;
+            imgproc::cvt_color(&img, &mut converted, code, 0)?;
+            converted
+        }
+        None => img,
+    };
+    // Sixteen bit images, which PNG originals may be, use the whole range of 16 bits.
+    let scale = if img.depth()? == core::CV_16U {
+        257.0
+    } else {
+        1.0
+    };
+    let value = core::Scalar::new(
+        f64::from(background.blue) * scale,
+        f64::from(background.green) * scale,
+        f64::from(background.red) * scale,
+        f64::from(background.alpha) * scale,
+    );
+    Ok((img, value))
+}
+
+fn pad_image(
+    img: core::Mat,
+    canvas: &Region,
+    background: &Color,
+) -> Result<core::Mat, opencv::Error> {
+    let (img, value) = with_background(img, background)?;
+    let mut result = core::Mat::default()?;
+    core::copy_make_border(
+        &img,
+        &mut result,
+        canvas.y,
+        canvas.height - img.rows()? - canvas.y,
+        canvas.x,
+        canvas.width - img.cols()? - canvas.x,
+        core::BORDER_CONSTANT,
+        value,
     )?;
+    Ok(result)
+}
 
+/// Expanded images are scaled down to at most `max_pixels` pixels.
+fn rotate_image_by(
+    img: core::Mat,
+    degrees: f64,
+    expand: bool,
+    max_pixels: u64,
+    background: &Color,
+) -> Result<core::Mat, opencv::Error> {
+    let (matrix, (width, height)) =
+        get_rotation(img.cols()?, img.rows()?, degrees, expand, max_pixels);
+    let (img, value) = with_background(img, background)?;
+    let matrix = core::Mat::from_slice(&matrix)?.reshape(1, 2)?;
+    let mut result = core::Mat::default()?;
+    imgproc::warp_affine(
+        &img,
+        &mut result,
+        &matrix,
+        core::Size { width, height },
+        imgproc::INTER_LINEAR,
+        core::BORDER_CONSTANT,
+        value,
+    )?;
     Ok(result)
 }
+
+fn flip_image(img: core::Mat, flip: bool, flop: bool) -> Result<core::Mat, opencv::Error> {
+    let flip_code = match (flip, flop) {
+        (true, true) => -1,
+        (true, false) => 0,
+        (false, true) => 1,
+        (false, false) => return Ok(img),
+    };
+    let mut result_flip = core::Mat::default()?;
+    core::flip(&img, &mut result_flip, flip_code)?;
+    Ok(result_flip)
+}
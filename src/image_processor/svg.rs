@@ -0,0 +1,215 @@
+use crate::commons::errors::*;
+use crate::commons::geometry::limit_pixels;
+use crate::commons::*;
+
+use magick_rust::bindings::{
+    FilterType_TriangleFilter, MagickGetImageResolution, MagickSetBackgroundColor, MagickSetFormat,
+    MagickSetResolution,
+};
+use magick_rust::{MagickWand, PixelWand};
+use std::ffi::CString;
+
+/// How far into the document the root `svg` element is looked for.
+const SNIFF_LENGTH: usize = 4096;
+/// Density ImageMagick renders SVG images at when the document does not set one.
+const DEFAULT_DENSITY: f64 = 96.0;
+
+pub fn is_svg(buffer: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&buffer[..buffer.len().min(SNIFF_LENGTH)]);
+    let head = head.trim_start_matches(|c: char| c == '\u{feff}' || c.is_whitespace());
+    head.starts_with('<') && head.contains("<svg")
+}
+
+fn is_local_reference(reference: &str) -> bool {
+    let reference = reference.trim();
+    reference.starts_with('#')
+        || (reference.starts_with("data:") && !reference.starts_with("data:image/svg"))
+}
+
+/// Values of the `href` attributes (`xlink:href` included) and of the CSS `url()` functions.
+fn references(document: &str) -> Vec<&str> {
+    let mut references = Vec::new();
+    let mut rest = document;
+    while let Some(start) = rest.find("href") {
+        rest = &rest[start + "href".len()..];
+        let value = rest.trim_start();
+        if !value.starts_with('=') {
+            continue;
+        }
+        let value = value[1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => continue,
+        };
+        let value = &value[1..];
+        references.push(&value[..value.find(quote).unwrap_or_else(|| value.len())]);
+    }
+    let mut rest = document;
+    while let Some(start) = rest.find("url(") {
+        rest = &rest[start + "url(".len()..];
+        let value = &rest[..rest.find(')').unwrap_or_else(|| rest.len())];
+        references.push(value.trim().trim_matches(|c| c == '"' || c == '\''));
+    }
+    references
+}
+
+/// Checks the document only references itself or embedded data, so rendering it never fetches
+/// anything. A `DOCTYPE` without an internal subset, as written by most editors, is dropped
+/// since its external DTD is not needed to render the image.
+fn sanitise(buffer: &[u8]) -> Result<Vec<u8>, UnsupportedFormatError> {
+    let document = String::from_utf8_lossy(buffer).into_owned();
+    // ASCII lowercasing keeps byte offsets, so positions are valid in both strings.
+    let lowercase = document.to_ascii_lowercase();
+    let mut document = document;
+    if let Some(start) = lowercase.find("<!doctype") {
+        let end = lowercase[start..]
+            .find('>')
+            .map(|end| start + end + 1)
+            .ok_or_else(|| UnsupportedFormatError::new("the SVG DOCTYPE is not closed"))?;
+        if lowercase[start..end].contains('[') {
+            return Err(UnsupportedFormatError::new(
+                "SVG images with a DOCTYPE internal subset are not supported",
+            ));
+        }
+        document.replace_range(start..end, "");
+    }
+    let lowercase = document.to_ascii_lowercase();
+    if lowercase.contains("<!doctype") || lowercase.contains("<!entity") {
+        return Err(UnsupportedFormatError::new(
+            "SVG images with entities are not supported",
+        ));
+    }
+    if lowercase.contains("@import") {
+        return Err(UnsupportedFormatError::new(
+            "SVG images with CSS imports are not supported",
+        ));
+    }
+    if let Some(reference) = references(&lowercase)
+        .into_iter()
+        .find(|reference| !is_local_reference(reference))
+    {
+        return Err(UnsupportedFormatError::new(&format!(
+            "SVG images with external references are not supported, found {:?}",
+            reference
+        )));
+    }
+    Ok(document.into_bytes())
+}
+
+fn read(document: &[u8], resolution: Option<(f64, f64)>) -> Result<MagickWand, MagickError> {
+    let wand = MagickWand::new();
+    let mut background = PixelWand::new();
+    background.set_color("transparent")?;
+    let format = CString::new("SVG").expect("Format has no NUL bytes");
+    unsafe {
+        MagickSetFormat(wand.wand, format.as_ptr());
+        MagickSetBackgroundColor(wand.wand, background.wand);
+        if let Some((x, y)) = resolution {
+            MagickSetResolution(wand.wand, x, y);
+        }
+    }
+    wand.read_image_blob(document)?;
+    Ok(wand)
+}
+
+/// Renders the SVG straight at the size it is displayed at, instead of scaling a rendering at
+/// its intrinsic size, and encodes it as PNG. With `Cover` the rendering covers the size, so
+/// it is left to crop. Renderings are kept within `max_pixels` pixels.
+pub fn rasterise(
+    buffer: &[u8],
+    size: &Size,
+    fit: Fit,
+    max_pixels: u64,
+) -> Result<Vec<u8>, opencv::Error> {
+    let document = sanitise(buffer)?;
+    let wand = read(&document, None)?;
+    let width = wand.get_image_width() as i32;
+    let height = wand.get_image_height() as i32;
+    let (target_width, target_height) = get_scaled_size(width, height, size, fit)?;
+    let (target_width, target_height) = limit_pixels(target_width, target_height, max_pixels);
+    debug!(
+        "Rasterising SVG. Intrinsic size: {}x{}. Final size: {}x{}",
+        width, height, target_width, target_height
+    );
+    let wand = if (target_width, target_height) == (width, height) {
+        wand
+    } else {
+        let (mut x_resolution, mut y_resolution) = (0f64, 0f64);
+        unsafe {
+            MagickGetImageResolution(wand.wand, &mut x_resolution, &mut y_resolution);
+        }
+        let density = |resolution: f64, target: i32, intrinsic: i32| {
+            let resolution = if resolution > 0.0 {
+                resolution
+            } else {
+                DEFAULT_DENSITY
+            };
+            resolution * f64::from(target) / f64::from(intrinsic)
+        };
+        let scaled = read(
+            &document,
+            Some((
+                density(x_resolution, target_width, width),
+                density(y_resolution, target_height, height),
+            )),
+        )?;
+        // The rendering can be a pixel off the target size due to rounding.
+        scaled.resize_image(
+            target_width as usize,
+            target_height as usize,
+            FilterType_TriangleFilter,
+        );
+        scaled
+    };
+    wand.write_image_blob("png")
+        .map_err(|e| MagickError::from(e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGO: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="10" height="10">
+  <defs><linearGradient id="g"/></defs>
+  <rect width="10" height="10" fill="url(#g)"/>
+  <use xlink:href="#g"/>
+  <image href="data:image/png;base64,iVBORw0KGgo="/>
+</svg>"##;
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(LOGO.as_bytes()));
+        assert!(is_svg(b"\xEF\xBB\xBF  <svg width=\"1\"/>"));
+        assert!(!is_svg(b"<html><body></body></html>"));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n<svg"));
+    }
+
+    #[test]
+    fn test_sanitise_local_references() {
+        assert_eq!(sanitise(LOGO.as_bytes()), Ok(LOGO.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_sanitise_drops_doctype() {
+        let document = r#"<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd"><svg/>"#;
+        assert_eq!(sanitise(document.as_bytes()), Ok(b"<svg/>".to_vec()));
+    }
+
+    #[test]
+    fn test_sanitise_external_references() {
+        let documents = [
+            r#"<svg><image href="http://example.com/a.png"/></svg>"#,
+            r#"<svg><image xlink:href = 'file:///etc/passwd'/></svg>"#,
+            r#"<svg><use HREF="other.svg#logo"/></svg>"#,
+            r#"<svg><image href="data:image/svg+xml;base64,PHN2Zz4="/></svg>"#,
+            r#"<svg><rect style="fill: url( 'http://example.com/a' )"/></svg>"#,
+            r#"<svg><style>@import "http://example.com/a.css";</style></svg>"#,
+            r#"<!DOCTYPE svg [<!ENTITY e SYSTEM "file:///etc/passwd">]><svg>&e;</svg>"#,
+            r#"<svg><!ENTITY e "e"></svg>"#,
+        ];
+        for document in documents.iter() {
+            assert!(sanitise(document.as_bytes()).is_err(), "{}", document);
+        }
+    }
+}
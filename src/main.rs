@@ -6,101 +6,362 @@ extern crate actix_web;
 extern crate actix_web_prom;
 extern crate config;
 extern crate futures;
+extern crate linked_hash_map;
 extern crate opencv;
+extern crate percent_encoding;
 extern crate pretty_env_logger;
+extern crate prometheus;
 extern crate rusoto_s3;
+extern crate serde_json;
 extern crate serde_qs;
+extern crate sha1;
 
+mod cache;
 mod commons;
 mod image_processor;
 
-use commons::s3;
+use cache::{CacheKey, CachedImage, ImageCache};
+use commons::conditional::{derive_validators, is_conditional, is_not_modified, Validators};
+use commons::errors::into_http_error;
+use commons::geometry::Resize;
+use commons::negotiation::AcceptedFormats;
+use commons::source::{normalize_key, validate_key, ImageSources};
 use commons::*;
 
 use actix_http::{HttpService, KeepAlive};
 use actix_server::Server;
-use actix_web::{dev::Body, web, App, HttpRequest, HttpResponse};
+use actix_web::dev::{Body, HttpResponseBuilder};
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{web, App, HttpRequest, HttpResponse};
 use actix_web_prom::PrometheusMetrics;
-use futures::future::{join_all, Future};
+use futures::future::{join_all, Either, Future};
 use image_processor::*;
 use magick_rust::magick_wand_genesis;
-use rusoto_s3::S3Client;
 use std::env;
-use std::sync::{Arc, Once};
+use std::sync::Once;
 
 static START: Once = Once::new();
 
 fn index(
     req: HttpRequest,
-    path: web::Path<String>,
     qs_config: web::Data<serde_qs::Config>,
-    s3_client: web::Data<S3Client>,
+    sources: web::Data<ImageSources>,
+    cache: web::Data<ImageCache>,
+    config: web::Data<Configuration>,
+) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let resolved = normalize_key(req.uri().path().get(1..).unwrap_or(""))
+        .map_err(actix_web::error::ErrorBadRequest)
+        .and_then(|path| {
+            sources
+                .resolve(&path, config.default_source.as_ref().map(String::as_str))
+                .ok_or_else(|| actix_web::error::ErrorNotFound(format!("File {} not found", path)))
+        });
+    let source = resolved.as_ref().ok().map(|(source, _)| source.clone());
+    let config_cp = config.clone();
+    futures::done(resolved)
+        .and_then(move |(source, key)| {
+            process_image(req, source, key, qs_config, sources, cache, config)
+        })
+        .then(move |res| {
+            let mut response = res.unwrap_or_else(HttpResponse::from_error);
+            let cache_control = config_cp
+                .cache_control
+                .for_status(source.as_ref().map(String::as_str), response.status());
+            if let Some(value) = cache_control {
+                response.headers_mut().insert(
+                    header::CACHE_CONTROL,
+                    header::HeaderValue::from_str(value)
+                        .expect("Cache-Control values are validated on startup"),
+                );
+            }
+            Ok(response)
+        })
+}
+
+fn process_image(
+    req: HttpRequest,
+    source: String,
+    key: String,
+    qs_config: web::Data<serde_qs::Config>,
+    sources: web::Data<ImageSources>,
+    cache: web::Data<ImageCache>,
     config: web::Data<Configuration>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
     let rs_query = qs_config
         .deserialize_str::<ProcessImageRequest>(req.query_string())
-        .map_err(actix_web::error::ErrorBadRequest);
-    futures::done(rs_query).and_then(move |query| {
-        debug!("Request parameters: {:?}", query);
+        .map_err(actix_web::error::ErrorBadRequest)
+        .and_then(|query| {
+            for wm in &query.watermarks {
+                validate_key(&wm.filename).map_err(actix_web::error::ErrorBadRequest)?;
+            }
+            query
+                .validate(&config.jpeg)
+                .map_err(actix_web::error::ErrorBadRequest)?;
+            Ok(query)
+        });
+    let negotiated = match &rs_query {
+        Ok(query) => query.format == ImageFormat::Auto,
+        Err(_) => false,
+    };
+    let accepted = AcceptedFormats::from_request(&req);
+    let req_cp = req.clone();
+    futures::done(rs_query)
+        .and_then(move |query| {
+            debug!("Request parameters: {:?}", query);
+
+            let options = EncodeOptions::new(&query, &config);
+            let cache_key = CacheKey::new(&source, &key, &query, &options, &accepted);
+            let current = {
+                let sources = sources.clone();
+                let source = source.clone();
+                let key = key.clone();
+                let watermarks = watermark_keys(&query);
+                let wm_source = watermark_source(&config, &source);
+                let transformation = cache_key.transformation().to_string();
+                move || {
+                    current_validators(
+                        &sources,
+                        &source,
+                        &key,
+                        &watermarks,
+                        &wm_source,
+                        &transformation,
+                    )
+                }
+            };
+            let lookup = cache.get(&cache_key, current);
+            lookup.and_then(move |cached| match cached {
+                Some(image) => Either::A(futures::ok(Outcome::Image(image))),
+                None => Either::B(
+                    revalidate(&req, &source, &key, &query, &cache_key, &sources, &config)
+                        .and_then(move |not_modified| {
+                            if let Some(validators) = not_modified {
+                                return Either::A(futures::ok(Outcome::NotModified(validators)));
+                            }
+                            let transformed =
+                                sources.get_image(&source, &key).and_then(move |original| {
+                                    let validators = original.validators;
+                                    transform_image(
+                                        original.body,
+                                        query,
+                                        options,
+                                        &accepted,
+                                        &source,
+                                        &sources,
+                                        config,
+                                    )
+                                    .map(move |output| (output, validators))
+                                });
+                            Either::B(transformed.map(move |(output, validators)| {
+                                let (body, format, wm_validators) = output;
+                                let validators = derive_validators(
+                                    &validators,
+                                    &wm_validators,
+                                    cache_key.transformation(),
+                                );
+                                let image = CachedImage {
+                                    format,
+                                    body,
+                                    validators,
+                                };
+                                cache.insert(&cache_key, image.clone());
+                                Outcome::Image(image)
+                            }))
+                        }),
+                ),
+            })
+        })
+        .then(move |res| {
+            let mut response = index_response(&req_cp, res);
+            if negotiated {
+                response
+                    .headers_mut()
+                    .insert(header::VARY, header::HeaderValue::from_static("accept"));
+            }
+            Ok(response)
+        })
+}
+
+/// Source the watermarks of an image from `source` are read from.
+fn watermark_source(config: &Configuration, source: &str) -> String {
+    config
+        .watermark_source
+        .clone()
+        .unwrap_or_else(|| source.to_string())
+}
+
+/// Answers conditional requests for images which are not cached from the validators of the
+/// original and watermarks, without downloading them. Resolves to the validators when the
+/// client has the current image, and to `None` when the image has to be processed, including
+/// when a source cannot tell the validators.
+fn revalidate(
+    req: &HttpRequest,
+    source: &str,
+    key: &str,
+    query: &ProcessImageRequest,
+    cache_key: &CacheKey,
+    sources: &web::Data<ImageSources>,
+    config: &Configuration,
+) -> impl Future<Item = Option<Validators>, Error = actix_web::Error> {
+    if !is_conditional(req) {
+        return Either::A(futures::ok(None));
+    }
+    let req = req.clone();
+    Either::B(
+        current_validators(
+            sources,
+            source,
+            key,
+            &watermark_keys(query),
+            &watermark_source(config, source),
+            cache_key.transformation(),
+        )
+        .map(move |validators| validators.filter(|validators| is_not_modified(&req, validators))),
+    )
+}
+
+fn watermark_keys(query: &ProcessImageRequest) -> Vec<String> {
+    query
+        .watermarks
+        .iter()
+        .map(|wm| wm.filename.clone())
+        .collect()
+}
 
-        let ProcessImageRequest {
-            size,
+/// Validators the processed image would have now, from the validators of its original and
+/// watermarks. Resolves to `None` when a source cannot tell them.
+fn current_validators(
+    sources: &ImageSources,
+    source: &str,
+    key: &str,
+    watermarks: &[String],
+    wm_source: &str,
+    transformation: &str,
+) -> impl Future<Item = Option<Validators>, Error = actix_web::Error> {
+    let wm_validators = join_all(
+        watermarks
+            .iter()
+            .map(|wm| sources.get_validators(wm_source, wm))
+            .collect::<Vec<_>>(),
+    );
+    let transformation = transformation.to_string();
+    sources
+        .get_validators(source, key)
+        .join(wm_validators)
+        .then(move |res| match res {
+            Ok((original, watermarks)) => Ok(Some(derive_validators(
+                &original,
+                &watermarks,
+                &transformation,
+            ))),
+            Err(e) => {
+                debug!("Unable to revalidate the image: {:?}", e);
+                Ok(None)
+            }
+        })
+}
+
+fn transform_image(
+    body: Bytes,
+    query: ProcessImageRequest,
+    options: EncodeOptions,
+    accepted: &AcceptedFormats,
+    source: &str,
+    sources: &web::Data<ImageSources>,
+    config: web::Data<Configuration>,
+) -> impl Future<Item = (Bytes, ImageFormat, Vec<Validators>), Error = actix_web::Error> {
+    let resize = Resize::new(&query, config.max_output_pixels);
+    let wm_source = watermark_source(&config, source);
+    let sources_cp = sources.clone();
+    let wm_futures = query
+        .watermarks
+        .clone()
+        .into_iter()
+        .map(move |wm| sources_cp.get_image(&wm_source, &wm.filename));
+    futures::done(
+        pre_process_image(&body[..], &query, &resize, accepted, &options).map_err(|e| {
+            error!("Error processing image: {:?}", e);
+            into_http_error(e)
+        }),
+    )
+    .and_then(move |image| {
+        let EncodedImage {
+            body,
             format,
-            quality,
-            watermarks,
-            rotation,
-        } = query;
-        let bucket = Arc::new(config.bucket.clone());
-        let bucket_cp = bucket.clone();
-        let s3_client_cp = s3_client.clone();
-        let wm_futures = watermarks
-            .clone()
-            .into_iter()
-            .map(move |wm| s3::get_image(&s3_client_cp, &bucket_cp, &wm.filename));
-        s3::get_image(&s3_client, &bucket, &path)
-            .map(move |body| {
-                pre_process_image(
-                    &body[..],
-                    rotation,
-                    &size,
-                    format,
-                    quality,
-                    config.png_quality,
-                )
-                .map_err(|e| {
-                    error!("Error processing image: {:?}", e);
-                    actix_web::error::ErrorInternalServerError(e)
+            working_format,
+            profiles,
+        } = image;
+        join_all(wm_futures).and_then(move |wm_images| {
+            let wm_validators = wm_images
+                .iter()
+                .map(|wm_image| wm_image.validators.clone())
+                .collect::<Vec<_>>();
+            let (profiles, options) = (&profiles, &options);
+            wm_images
+                .iter()
+                .zip(query.watermarks)
+                .fold(Ok(body), move |current, item| {
+                    let (wm_image, wm) = item;
+                    apply_watermark(
+                        &current?,
+                        &wm_image.body[..],
+                        &wm,
+                        working_format,
+                        profiles,
+                        options,
+                    )
+                    .map_err(|e| e.into())
                 })
-            })
-            .map(move |body| {
-                join_all(wm_futures).map(move |buffers| {
-                    buffers
-                        .iter()
-                        .zip(watermarks)
-                        .fold(body, move |current, item| {
-                            let (wm_buffer, wm) = item;
-                            apply_watermark(&current?, &wm_buffer[..], &wm, format)
-                                .map_err(|e| e.into())
-                        })
+                .and_then(|body| {
+                    encode_output(body, working_format, format, options, profiles)
+                        .map_err(into_http_error)
                 })
-            })
-            .flatten()
-            .and_then(move |res| index_response(res, format))
+                .map(|body| (Bytes::from(body), format, wm_validators))
+        })
     })
 }
 
+/// What a request is answered with, before it is turned into a response.
+enum Outcome {
+    Image(CachedImage),
+    NotModified(Validators),
+}
+
+fn set_validators(response: &mut HttpResponseBuilder, validators: &Validators) {
+    if let Some(etag) = &validators.etag {
+        response.header(header::ETAG, etag.as_str());
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        response.header(header::LAST_MODIFIED, last_modified.as_str());
+    }
+}
+
+fn not_modified_response(validators: &Validators) -> HttpResponse {
+    let mut response = HttpResponse::NotModified();
+    set_validators(&mut response, validators);
+    response.finish()
+}
+
 fn index_response(
-    res: Result<Vec<u8>, actix_web::error::Error>,
-    format: ImageFormat,
+    req: &HttpRequest,
+    res: Result<Outcome, actix_web::error::Error>,
 ) -> HttpResponse {
     match res {
         Err(e) => {
             error!("Error processing request: {:?}", e);
             HttpResponse::from_error(e)
         }
-        Ok(img_response) => HttpResponse::Ok()
-            .content_type(format!("image/{}", format).as_str())
-            .body(Body::from(img_response)),
+        Ok(Outcome::NotModified(validators)) => not_modified_response(&validators),
+        Ok(Outcome::Image(ref image)) if is_not_modified(req, &image.validators) => {
+            not_modified_response(&image.validators)
+        }
+        Ok(Outcome::Image(image)) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type(format!("image/{}", image.format).as_str());
+            set_validators(&mut response, &image.validators);
+            response.body(Body::from(image.body))
+        }
     }
 }
 
@@ -130,8 +391,13 @@ fn main() -> std::io::Result<()> {
     pretty_env_logger::init();
     let sys = actix_rt::System::builder().stop_on_panic(false).build();
     let prometheus = PrometheusMetrics::new(name, "/metrics");
-    let s3 = S3Client::new(config_data.region.clone());
-    let s3_client_data = web::Data::new(s3);
+    let sources = ImageSources::new(&config_data.sources);
+    let sources_data = web::Data::new(sources);
+    let cache = ImageCache::new(&config_data.cache).expect("Failed to open the image cache.");
+    cache
+        .register_metrics(&prometheus.registry)
+        .expect("Failed to register cache metrics");
+    let cache_data = web::Data::new(cache);
     //accept url encoded with brackets or their encoded equivalents
     let qs_config = serde_qs::Config::new(5, false);
     let qs_config_data = web::Data::new(qs_config);
@@ -142,14 +408,15 @@ fn main() -> std::io::Result<()> {
             format!("0.0.0.0:{}", config_data.app_port),
             move || {
                 HttpService::build().keep_alive(KeepAlive::Os).h1(App::new()
-                    .register_data(s3_client_data.clone())
+                    .register_data(sources_data.clone())
+                    .register_data(cache_data.clone())
                     .register_data(config_data.clone())
                     .register_data(qs_config_data.clone())
                     .wrap(prometheus.clone())
                     .wrap(actix_web::middleware::Logger::default())
                     .service(web::resource("/health").route(web::get().to(health)))
                     .service(web::resource("/test").route(web::get().to(test)))
-                    .service(web::resource("/{file_name}").route(web::get().to_async(index))))
+                    .service(web::resource("/{path:.*}").route(web::get().to_async(index))))
             },
         )?
         .start();
@@ -1,5 +1,7 @@
 mod utils;
 
+use actix_web::http::StatusCode;
+
 #[test]
 fn test_get_simple() {
     let result = utils::make_request(&utils::RequestParametersBuilder::new("img-test"))
@@ -24,6 +26,17 @@ fn test_get_resized() {
     utils::assert_result(&result[..], "resized.jpg");
 }
 
+#[test]
+fn test_get_resized_linear() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("img-test")
+            .with_size(100, 100)
+            .with_filter(utils::Filter::Linear),
+    )
+    .expect("Unable to download file");
+    utils::assert_result(&result[..], "resized_linear.jpg");
+}
+
 #[test]
 fn test_get_watermarked_left() {
     let result = utils::make_request(
@@ -170,6 +183,43 @@ fn test_get_multiple_watermarks() {
     utils::assert_result(&result[..], "multiple_watermarks.jpg");
 }
 
+#[test]
+fn test_get_multiple_watermarks_nearest() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("img-test")
+            .add_watermark(
+                "watermark",
+                50,
+                50,
+                0.3f64,
+                10,
+                10,
+                utils::WatermarkPosition::RightBottom,
+            )
+            .add_watermark(
+                "watermark",
+                50,
+                50,
+                0.3f64,
+                10,
+                10,
+                utils::WatermarkPosition::Center,
+            )
+            .add_watermark(
+                "watermark",
+                50,
+                50,
+                0.3f64,
+                10,
+                10,
+                utils::WatermarkPosition::LeftTop,
+            )
+            .with_watermark_filter(utils::Filter::Nearest),
+    )
+    .expect("Unable to download file");
+    utils::assert_result(&result[..], "multiple_watermarks_nearest.jpg");
+}
+
 #[test]
 fn test_get_all_features() {
     let result = utils::make_request(
@@ -200,3 +250,297 @@ fn test_get_all_features() {
     .expect("Unable to download file");
     utils::assert_result(&result[..], "all_features.webp");
 }
+
+#[test]
+fn test_get_all_features_explicit_filters() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("img-test")
+            .with_format(utils::ImageFormat::Webp)
+            .with_quality(50)
+            .with_rotation(utils::Rotation::R180)
+            .add_watermark(
+                "watermark",
+                50,
+                50,
+                0.3f64,
+                10,
+                10,
+                utils::WatermarkPosition::RightBottom,
+            )
+            .add_watermark(
+                "watermark",
+                50,
+                50,
+                0.3f64,
+                10,
+                10,
+                utils::WatermarkPosition::LeftTop,
+            )
+            .with_size(150, 150)
+            .with_filter(utils::Filter::Linear)
+            .with_watermark_filter(utils::Filter::Nearest),
+    )
+    .expect("Unable to download file");
+    utils::assert_result(&result[..], "all_features_explicit_filters.webp");
+}
+
+#[test]
+fn test_get_adobe_rgb_converted() {
+    let result = utils::make_request(&utils::RequestParametersBuilder::new("adobe-rgb"))
+        .expect("Unable to download file");
+    utils::assert_colour(&result[..], 16, 16, [232, 57, 57]);
+    utils::assert_colour(&result[..], 48, 16, [0, 201, 37]);
+    utils::assert_colour(&result[..], 16, 48, [57, 57, 205]);
+    utils::assert_colour(&result[..], 48, 48, [129, 129, 129]);
+}
+
+#[test]
+fn test_get_adobe_rgb_preserved() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("adobe-rgb").with_icc(utils::Icc::Preserve),
+    )
+    .expect("Unable to download file");
+    utils::assert_colour(&result[..], 16, 16, [200, 60, 60]);
+    utils::assert_colour(&result[..], 48, 48, [128, 128, 128]);
+}
+
+#[test]
+fn test_get_cmyk() {
+    let result = utils::make_request(&utils::RequestParametersBuilder::new("cmyk"))
+        .expect("Unable to download file");
+    utils::assert_colour(&result[..], 16, 16, [0, 255, 255]);
+    utils::assert_colour(&result[..], 48, 16, [255, 0, 255]);
+    utils::assert_colour(&result[..], 16, 48, [255, 255, 0]);
+    utils::assert_colour(&result[..], 48, 48, [0, 0, 0]);
+}
+
+#[test]
+fn test_get_gps_stripped() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("gps").with_metadata(utils::Metadata::StripAll),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_property(&result[..], "exif:GPSLatitude"), None);
+    assert_eq!(utils::image_property(&result[..], "exif:Copyright"), None);
+}
+
+#[test]
+fn test_get_gps_keep_copyright() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("gps").with_metadata(utils::Metadata::KeepCopyright),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_property(&result[..], "exif:GPSLatitude"), None);
+    assert_eq!(utils::image_property(&result[..], "exif:Make"), None);
+    assert_eq!(
+        utils::image_property(&result[..], "exif:Copyright"),
+        Some("Copyright Rustbier".to_string())
+    );
+}
+
+#[test]
+fn test_get_gps_watermarked_keep_copyright() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("gps")
+            .with_metadata(utils::Metadata::KeepCopyright)
+            .add_watermark(
+                "watermark",
+                50,
+                50,
+                0.3f64,
+                10,
+                10,
+                utils::WatermarkPosition::Center,
+            ),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_property(&result[..], "exif:GPSLatitude"), None);
+    assert_eq!(
+        utils::image_property(&result[..], "exif:Copyright"),
+        Some("Copyright Rustbier".to_string())
+    );
+}
+
+#[test]
+fn test_get_animated_gif() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("animated-gif").with_size(30, 30),
+    )
+    .expect("Unable to download file");
+    assert!(result.starts_with(b"GIF89a"));
+    assert_eq!(utils::frame_count(&result[..]), 3);
+    assert_eq!(utils::image_size(&result[..]), (30, 20));
+}
+
+#[test]
+fn test_get_animated_gif_as_webp() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("animated-gif")
+            .with_format(utils::ImageFormat::Webp)
+            .with_rotation(utils::Rotation::R90),
+    )
+    .expect("Unable to download file");
+    assert!(result.starts_with(b"RIFF") && &result[8..12] == b"WEBP");
+    assert_eq!(utils::frame_count(&result[..]), 3);
+    assert_eq!(utils::image_size(&result[..]), (40, 60));
+}
+
+#[test]
+fn test_get_animated_webp() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("animated-webp")
+            .with_format(utils::ImageFormat::Webp)
+            .with_size(30, 30),
+    )
+    .expect("Unable to download file");
+    assert!(result.starts_with(b"RIFF") && &result[8..12] == b"WEBP");
+    assert_eq!(utils::frame_count(&result[..]), 3);
+    assert_eq!(utils::image_size(&result[..]), (30, 20));
+}
+
+#[test]
+fn test_get_animated_webp_as_gif() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("animated-webp")
+            .with_format(utils::ImageFormat::Gif)
+            .add_watermark(
+                "watermark",
+                20,
+                20,
+                0.5f64,
+                0,
+                0,
+                utils::WatermarkPosition::Center,
+            ),
+    )
+    .expect("Unable to download file");
+    assert!(result.starts_with(b"GIF89a"));
+    assert_eq!(utils::frame_count(&result[..]), 3);
+    assert_eq!(utils::image_size(&result[..]), (60, 40));
+}
+
+#[test]
+fn test_get_animated_gif_first_frame() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("animated-gif").with_format(utils::ImageFormat::Png),
+    )
+    .expect("Unable to download file");
+    assert!(result.starts_with(b"\x89PNG"));
+    assert_eq!(utils::frame_count(&result[..]), 1);
+    assert_eq!(utils::image_size(&result[..]), (60, 40));
+    utils::assert_colour(&result[..], 50, 35, [220, 40, 40]);
+}
+
+#[test]
+fn test_get_tiff_first_page() {
+    let result = utils::make_request(&utils::RequestParametersBuilder::new("multipage-tiff"))
+        .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (60, 40));
+    utils::assert_colour(&result[..], 10, 10, [255, 0, 0]);
+}
+
+#[test]
+fn test_get_tiff_page() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("multipage-tiff")
+            .with_page(1)
+            .with_format(utils::ImageFormat::Png),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (30, 50));
+    utils::assert_colour(&result[..], 15, 25, [90, 90, 90]);
+}
+
+#[test]
+fn test_get_tiff_invalid_page() {
+    let status =
+        utils::request_status(&utils::RequestParametersBuilder::new("multipage-tiff").with_page(2))
+            .expect("Unable to download file");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_get_page_of_single_page_format() {
+    let status =
+        utils::request_status(&utils::RequestParametersBuilder::new("img-test").with_page(1))
+            .expect("Unable to download file");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_get_unsupported_format() {
+    let status = utils::request_status(&utils::RequestParametersBuilder::new("unsupported-pdf"))
+        .expect("Unable to download file");
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[test]
+fn test_get_svg_rasterised_at_size() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("logo-svg")
+            .with_format(utils::ImageFormat::Png)
+            .with_size(400, 400),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (400, 200));
+    utils::assert_colour(&result[..], 10, 10, [30, 144, 255]);
+    utils::assert_colour(&result[..], 200, 100, [255, 255, 255]);
+}
+
+#[test]
+fn test_get_svg_watermark() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("img-test").add_watermark(
+            "logo-svg",
+            100,
+            100,
+            1f64,
+            0,
+            0,
+            utils::WatermarkPosition::LeftTop,
+        ),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (250, 187));
+    utils::assert_colour(&result[..], 5, 5, [30, 144, 255]);
+    utils::assert_colour(&result[..], 50, 25, [255, 255, 255]);
+}
+
+#[test]
+fn test_get_svg_external_reference() {
+    let status = utils::request_status(&utils::RequestParametersBuilder::new("external-svg"))
+        .expect("Unable to download file");
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[test]
+fn test_get_exif_rotated() {
+    let result = utils::make_request(&utils::RequestParametersBuilder::new("exif-rotated"))
+        .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (187, 250));
+    utils::assert_result(&result[..], "raw_rotated.jpg");
+}
+
+#[test]
+fn test_get_exif_rotated_not_oriented() {
+    let result = utils::make_request(
+        &utils::RequestParametersBuilder::new("exif-rotated").with_auto_orient(false),
+    )
+    .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (250, 187));
+    utils::assert_result(&result[..], "raw.jpg");
+}
+
+#[test]
+fn test_get_exif_rotated_png() {
+    let result = utils::make_request(&utils::RequestParametersBuilder::new("exif-rotated-png"))
+        .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (187, 250));
+}
+
+#[test]
+fn test_get_exif_rotated_webp() {
+    let result = utils::make_request(&utils::RequestParametersBuilder::new("exif-rotated-webp"))
+        .expect("Unable to download file");
+    assert_eq!(utils::image_size(&result[..]), (187, 250));
+}
@@ -1,13 +1,21 @@
 use actix_http::client::SendRequestError;
 use actix_rt::System;
 use actix_web::client::Client;
+use actix_web::http::StatusCode;
 use bytes::Bytes;
 use futures::future::lazy;
 use futures::future::Future;
-use magick_rust::bindings::MetricType_PerceptualHashErrorMetric;
-use magick_rust::{magick_wand_genesis, MagickWand};
+use magick_rust::bindings::{
+    MagickGetImagePixelColor, MagickGetImageProperty, MagickGetNumberImages,
+    MagickRelinquishMemory, MagickSetIteratorIndex, MetricType_PerceptualHashErrorMetric,
+    PixelGetBlue, PixelGetGreen, PixelGetRed,
+};
+use magick_rust::{magick_wand_genesis, MagickWand, PixelWand};
 use std::env;
+use std::ffi::{CStr, CString};
 use std::fmt;
+use std::fs;
+use std::os::raw::c_void;
 use std::sync::Once;
 
 static START: Once = Once::new();
@@ -20,6 +28,12 @@ pub struct RequestParametersBuilder {
     h: Option<i32>,
     watermarks: Vec<Watermark>,
     r: Option<Rotation>,
+    filter: Option<Filter>,
+    watermark_filter: Option<Filter>,
+    icc: Option<Icc>,
+    metadata: Option<Metadata>,
+    page: Option<usize>,
+    auto_orient: Option<bool>,
 }
 
 pub struct Watermark {
@@ -44,10 +58,26 @@ pub enum Rotation {
     R270,
 }
 
+pub enum Filter {
+    Nearest,
+    Linear,
+}
+
+pub enum Icc {
+    Convert,
+    Preserve,
+}
+
+pub enum Metadata {
+    StripAll,
+    KeepCopyright,
+}
+
 pub enum ImageFormat {
     Png,
     Jpeg,
     Webp,
+    Gif,
 }
 
 impl RequestParametersBuilder {
@@ -60,6 +90,12 @@ impl RequestParametersBuilder {
             h: None,
             watermarks: Vec::new(),
             r: None,
+            filter: None,
+            watermark_filter: None,
+            icc: None,
+            metadata: None,
+            page: None,
+            auto_orient: None,
         }
     }
 
@@ -78,6 +114,36 @@ impl RequestParametersBuilder {
         self
     }
 
+    pub fn with_filter(&mut self, filter: Filter) -> &mut Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn with_watermark_filter(&mut self, filter: Filter) -> &mut Self {
+        self.watermark_filter = Some(filter);
+        self
+    }
+
+    pub fn with_icc(&mut self, icc: Icc) -> &mut Self {
+        self.icc = Some(icc);
+        self
+    }
+
+    pub fn with_metadata(&mut self, metadata: Metadata) -> &mut Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn with_page(&mut self, page: usize) -> &mut Self {
+        self.page = Some(page);
+        self
+    }
+
+    pub fn with_auto_orient(&mut self, auto_orient: bool) -> &mut Self {
+        self.auto_orient = Some(auto_orient);
+        self
+    }
+
     pub fn with_size(&mut self, width: i32, height: i32) -> &mut Self {
         self.w = Some(width);
         self.h = Some(height);
@@ -115,6 +181,9 @@ pub fn assert_result(img: &[u8], filename: &str) {
     wand1.read_image_blob(img).expect("Unable to read response image");
     let wand2 = MagickWand::new();
     let file_result = format!("tests/results/{}", filename);
+    if env::var("RUSTBIER_UPDATE_RESULTS").is_ok() {
+        fs::write(&file_result, img).expect("Unable to write result image");
+    }
     wand2.read_image(&file_result).expect("Unable to result image");
 
     let (diff, _res_wand) = wand1.compare_images(&wand2, MetricType_PerceptualHashErrorMetric);
@@ -122,6 +191,63 @@ pub fn assert_result(img: &[u8], filename: &str) {
     assert!(diff == 0.0);
 }
 
+pub fn read_image(img: &[u8]) -> MagickWand {
+    START.call_once(|| {
+        magick_wand_genesis();
+    });
+    let wand = MagickWand::new();
+    wand.read_image_blob(img)
+        .expect("Unable to read response image");
+    wand
+}
+
+/// Width and height of the first frame of the response.
+pub fn image_size(img: &[u8]) -> (usize, usize) {
+    let wand = read_image(img);
+    unsafe {
+        MagickSetIteratorIndex(wand.wand, 0);
+    }
+    (wand.get_image_width(), wand.get_image_height())
+}
+
+pub fn frame_count(img: &[u8]) -> usize {
+    let wand = read_image(img);
+    unsafe { MagickGetNumberImages(wand.wand) }
+}
+
+/// Checks the colour of a pixel of the response, within the error of the JPEG encoding.
+pub fn assert_colour(img: &[u8], x: isize, y: isize, expected: [u8; 3]) {
+    let wand = read_image(img);
+    let pixel = PixelWand::new();
+    let colour = unsafe {
+        MagickGetImagePixelColor(wand.wand, x, y, pixel.wand);
+        [
+            PixelGetRed(pixel.wand),
+            PixelGetGreen(pixel.wand),
+            PixelGetBlue(pixel.wand),
+        ]
+    };
+    println!("Colour at {}x{}: {:?}", x, y, colour);
+    for (value, expected) in colour.iter().zip(expected.iter()) {
+        assert!((value * 255.0 - f64::from(*expected)).abs() <= 4.0);
+    }
+}
+
+/// Property of the response ImageMagick reads from its metadata, such as `exif:Copyright`.
+pub fn image_property(img: &[u8], name: &str) -> Option<String> {
+    let wand = read_image(img);
+    let name = CString::new(name).expect("Property name has no NUL bytes");
+    unsafe {
+        let value = MagickGetImageProperty(wand.wand, name.as_ptr());
+        if value.is_null() {
+            return None;
+        }
+        let property = CStr::from_ptr(value).to_string_lossy().into_owned();
+        MagickRelinquishMemory(value as *mut c_void);
+        Some(property)
+    }
+}
+
 pub fn make_request(params: &RequestParametersBuilder) -> Result<Bytes, SendRequestError> {
     System::new("test").block_on(lazy(|| {
         let client = Client::default();
@@ -141,6 +267,24 @@ pub fn make_request(params: &RequestParametersBuilder) -> Result<Bytes, SendRequ
     }))
 }
 
+pub fn request_status(params: &RequestParametersBuilder) -> Result<StatusCode, SendRequestError> {
+    System::new("test").block_on(lazy(|| {
+        let client = Client::default();
+
+        let url = get_url(&params);
+        println!("URL: {}", url);
+
+        client
+            .get(url)
+            .header("User-Agent", "Actix-web")
+            .send()
+            .map(|response| {
+                println!("Response: {:?}", response);
+                response.status()
+            })
+    }))
+}
+
 fn get_url(params: &RequestParametersBuilder) -> String {
     let mut query_string = Vec::new();
     if let Some(format) = &params.format {
@@ -158,6 +302,21 @@ fn get_url(params: &RequestParametersBuilder) -> String {
     if let Some(rotation) = &params.r {
         query_string.push(format!("rotation={}", rotation));
     }
+    if let Some(filter) = &params.filter {
+        query_string.push(format!("filter={}", filter));
+    }
+    if let Some(icc) = &params.icc {
+        query_string.push(format!("icc={}", icc));
+    }
+    if let Some(metadata) = &params.metadata {
+        query_string.push(format!("metadata={}", metadata));
+    }
+    if let Some(page) = params.page {
+        query_string.push(format!("page={}", page));
+    }
+    if let Some(auto_orient) = params.auto_orient {
+        query_string.push(format!("auto_orient={}", auto_orient));
+    }
     for (i, item) in params.watermarks.iter().enumerate() {
         query_string.push(format!("watermarks[{}][filename]={}", i, item.filename));
         query_string.push(format!("watermarks[{}][alpha]={}", i, item.alpha));
@@ -166,6 +325,9 @@ fn get_url(params: &RequestParametersBuilder) -> String {
         query_string.push(format!("watermarks[{}][origin]={}", i, item.origin));
         query_string.push(format!("watermarks[{}][position][x]={}", i, item.x));
         query_string.push(format!("watermarks[{}][position][y]={}", i, item.y));
+        if let Some(filter) = &params.watermark_filter {
+            query_string.push(format!("watermarks[{}][filter]={}", i, filter));
+        }
     }
 
     format!(
@@ -187,12 +349,43 @@ impl fmt::Display for WatermarkPosition {
     }
 }
 
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            Filter::Nearest => "Nearest",
+            Filter::Linear => "Linear",
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl fmt::Display for Icc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            Icc::Convert => "Convert",
+            Icc::Preserve => "Preserve",
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let as_str = match self {
+            Metadata::StripAll => "StripAll",
+            Metadata::KeepCopyright => "KeepCopyright",
+        };
+        write!(f, "{}", as_str)
+    }
+}
+
 impl fmt::Display for ImageFormat {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let as_str = match self {
             ImageFormat::Jpeg => "Jpeg",
             ImageFormat::Png => "Png",
             ImageFormat::Webp => "Webp",
+            ImageFormat::Gif => "Gif",
         };
         write!(f, "{}", as_str)
     }